@@ -0,0 +1,608 @@
+/* Arithmetic-coded JPEG (SOF9 and SOF10) scans.
+
+   Instead of Huffman codes, every coefficient is sent as a series of binary
+   decisions to the QM-coder of Annex D, which codes each decision with a
+   probability estimated from the earlier decisions made in the same context.
+   The statistics adapt to the image as it is coded, so no tables are sent; a
+   DAC segment only sets how the contexts are conditioned. Annex F gives the
+   decisions of sequential scans and Annex G those of progressive scans.
+
+   The coding below follows jcarith.c from libjpeg. */
+
+use super::progressive::{code_scan, default_scans, BlockCoder, Scan};
+use super::{
+    append_sos, for_each_mcu, starts_restart_interval, BlockSource, Frame, Quantizer, Writer,
+};
+
+/* Table D.2 of the JPEG spec: the probability estimate Qe of each state, the
+   next state after coding the less and the more probable symbol, and whether
+   the less probable symbol makes the two swap. The last state is not part of
+   the table; it holds a fixed estimate of 0.5 for decisions that are not
+   worth adapting to. */
+const QE_TABLE: [(u32, u8, u8, bool); 114] = [
+    (0x5a1d, 1, 1, true),
+    (0x2586, 14, 2, false),
+    (0x1114, 16, 3, false),
+    (0x080b, 18, 4, false),
+    (0x03d8, 20, 5, false),
+    (0x01da, 23, 6, false),
+    (0x00e5, 25, 7, false),
+    (0x006f, 28, 8, false),
+    (0x0036, 30, 9, false),
+    (0x001a, 33, 10, false),
+    (0x000d, 35, 11, false),
+    (0x0006, 9, 12, false),
+    (0x0003, 10, 13, false),
+    (0x0001, 12, 13, false),
+    (0x5a7f, 15, 15, true),
+    (0x3f25, 36, 16, false),
+    (0x2cf2, 38, 17, false),
+    (0x207c, 39, 18, false),
+    (0x17b9, 40, 19, false),
+    (0x1182, 42, 20, false),
+    (0x0cef, 43, 21, false),
+    (0x09a1, 45, 22, false),
+    (0x072f, 46, 23, false),
+    (0x055c, 48, 24, false),
+    (0x0406, 49, 25, false),
+    (0x0303, 51, 26, false),
+    (0x0240, 52, 27, false),
+    (0x01b1, 54, 28, false),
+    (0x0144, 56, 29, false),
+    (0x00f5, 57, 30, false),
+    (0x00b7, 59, 31, false),
+    (0x008a, 60, 32, false),
+    (0x0068, 62, 33, false),
+    (0x004e, 63, 34, false),
+    (0x003b, 32, 35, false),
+    (0x002c, 33, 9, false),
+    (0x5ae1, 37, 37, true),
+    (0x484c, 64, 38, false),
+    (0x3a0d, 65, 39, false),
+    (0x2ef1, 67, 40, false),
+    (0x261f, 68, 41, false),
+    (0x1f33, 69, 42, false),
+    (0x19a8, 70, 43, false),
+    (0x1518, 72, 44, false),
+    (0x1177, 73, 45, false),
+    (0x0e74, 74, 46, false),
+    (0x0bfb, 75, 47, false),
+    (0x09f8, 77, 48, false),
+    (0x0861, 78, 49, false),
+    (0x0706, 79, 50, false),
+    (0x05cd, 48, 51, false),
+    (0x04de, 50, 52, false),
+    (0x040f, 50, 53, false),
+    (0x0363, 51, 54, false),
+    (0x02d4, 52, 55, false),
+    (0x025c, 53, 56, false),
+    (0x01f8, 54, 57, false),
+    (0x01a4, 55, 58, false),
+    (0x0160, 56, 59, false),
+    (0x0125, 57, 60, false),
+    (0x00f6, 58, 61, false),
+    (0x00cb, 59, 62, false),
+    (0x00ab, 61, 63, false),
+    (0x008f, 61, 32, false),
+    (0x5b12, 65, 65, true),
+    (0x4d04, 80, 66, false),
+    (0x412c, 81, 67, false),
+    (0x37d8, 82, 68, false),
+    (0x2fe8, 83, 69, false),
+    (0x293c, 84, 70, false),
+    (0x2379, 86, 71, false),
+    (0x1edf, 87, 72, false),
+    (0x1aa9, 87, 73, false),
+    (0x174e, 72, 74, false),
+    (0x1424, 72, 75, false),
+    (0x119c, 74, 76, false),
+    (0x0f6b, 74, 77, false),
+    (0x0d51, 75, 78, false),
+    (0x0bb6, 77, 79, false),
+    (0x0a40, 77, 48, false),
+    (0x5832, 80, 81, true),
+    (0x4d1c, 88, 82, false),
+    (0x438e, 89, 83, false),
+    (0x3bdd, 90, 84, false),
+    (0x34ee, 91, 85, false),
+    (0x2eae, 92, 86, false),
+    (0x299a, 93, 87, false),
+    (0x2516, 86, 71, false),
+    (0x5570, 88, 89, true),
+    (0x4ca9, 95, 90, false),
+    (0x44d9, 96, 91, false),
+    (0x3e22, 97, 92, false),
+    (0x3824, 99, 93, false),
+    (0x32b4, 99, 94, false),
+    (0x2e17, 93, 86, false),
+    (0x56a8, 95, 96, true),
+    (0x4f46, 101, 97, false),
+    (0x47e5, 102, 98, false),
+    (0x41cf, 103, 99, false),
+    (0x3c3d, 104, 100, false),
+    (0x375e, 99, 93, false),
+    (0x5231, 105, 102, false),
+    (0x4c0f, 106, 103, false),
+    (0x4639, 107, 104, false),
+    (0x415e, 103, 99, false),
+    (0x5627, 105, 106, true),
+    (0x50e7, 108, 107, false),
+    (0x4b85, 109, 103, false),
+    (0x5597, 110, 109, false),
+    (0x504f, 111, 107, false),
+    (0x5a10, 110, 111, true),
+    (0x5522, 112, 109, false),
+    (0x59eb, 112, 111, true),
+    (0x5a1d, 113, 113, false),
+];
+
+const FIXED_STATE: u8 = 113;
+
+// Contexts per DC and per AC table.
+const DC_STAT_BINS: usize = 64;
+const AC_STAT_BINS: usize = 256;
+
+// Conditioning sent in the DAC segment, libjpeg's defaults. DC differences
+// with a magnitude below 2^L / 2 count as small and above 2^U / 2 as large;
+// AC coefficients up to K use the low-frequency magnitude contexts.
+const DC_L: u8 = 0;
+const DC_U: u8 = 1;
+const AC_K: u8 = 5;
+
+// Writes the conditioning of the DC and AC tables of a frame with
+// `num_tables` tables of each class.
+fn append_dac(out: &mut Writer, num_tables: usize) {
+    // DAC
+    out.write_u16(0xffcc);
+    // Len
+    out.write_u16(2 + 4 * num_tables as u16);
+    for t in 0..num_tables as u8 {
+        // tc_tb, then the conditioning
+        out.push(t);
+        out.push((DC_U << 4) | DC_L);
+        out.push(0x10 | t);
+        out.push(AC_K);
+    }
+}
+
+// The QM-coder of Annex D, writing to `out`.
+struct QmEncoder<'a, 'w> {
+    out: &'a mut Writer<'w>,
+    // Base of the coding interval, with the output byte in bits 19 to 26 and
+    // a carry in bit 27.
+    c: u32,
+    // Size of the coding interval.
+    a: u32,
+    // Stacked 0xff bytes, which a carry would turn into zeros.
+    sc: u32,
+    // Pending zero bytes, dropped if nothing else follows them.
+    zc: u32,
+    // Shifts left until the next byte is ready.
+    ct: u32,
+    // Last byte that is not 0xff, which a carry may still increment.
+    buffer: Option<u8>,
+}
+
+impl<'a, 'w> QmEncoder<'a, 'w> {
+    fn new(out: &'a mut Writer<'w>) -> QmEncoder<'a, 'w> {
+        QmEncoder {
+            out,
+            c: 0,
+            a: 0x10000,
+            sc: 0,
+            zc: 0,
+            ct: 11,
+            buffer: None,
+        }
+    }
+
+    fn emit_zeros(&mut self) {
+        for _ in 0..self.zc {
+            self.out.push(0);
+        }
+        self.zc = 0;
+    }
+
+    fn emit(&mut self, byte: u8) {
+        self.out.push(byte);
+        if byte == 0xff {
+            self.out.push(0);
+        }
+    }
+
+    // A carry reached the buffered byte: it goes out incremented, and the
+    // stacked 0xff bytes become zeros.
+    fn carry(&mut self) {
+        if let Some(byte) = self.buffer {
+            self.emit_zeros();
+            self.emit(byte + 1);
+        }
+        self.zc += self.sc;
+        self.sc = 0;
+    }
+
+    // No carry can reach the buffered byte or the stacked 0xff bytes any
+    // more.
+    fn settle(&mut self) {
+        match self.buffer {
+            Some(0) => self.zc += 1,
+            Some(byte) => {
+                self.emit_zeros();
+                self.emit(byte);
+            }
+            None => {}
+        }
+        if self.sc > 0 {
+            self.emit_zeros();
+            for _ in 0..self.sc {
+                self.emit(0xff);
+            }
+            self.sc = 0;
+        }
+    }
+
+    // Codes decision `val` in the context whose state is `st`, and updates
+    // the state. Bit 7 of the state is the more probable symbol and the low
+    // bits index QE_TABLE.
+    fn encode(&mut self, st: &mut u8, val: bool) {
+        let sv = *st;
+        let (qe, next_lps, next_mps, switch) = QE_TABLE[(sv & 0x7f) as usize];
+        let mps = sv & 0x80;
+
+        self.a -= qe;
+        if val != (mps != 0) {
+            // Less probable symbol. If its interval is the larger one, the
+            // two symbols trade intervals.
+            if self.a >= qe {
+                self.c += self.a;
+                self.a = qe;
+            }
+            *st = if switch { mps ^ 0x80 } else { mps } | next_lps;
+        } else {
+            // More probable symbol.
+            if self.a >= 0x8000 {
+                return;
+            }
+            if self.a < qe {
+                self.c += self.a;
+                self.a = qe;
+            }
+            *st = mps | next_mps;
+        }
+
+        // Renormalization, writing out bytes as they are done.
+        loop {
+            self.a <<= 1;
+            self.c <<= 1;
+            self.ct -= 1;
+            if self.ct == 0 {
+                let byte = self.c >> 19;
+                if byte > 0xff {
+                    self.carry();
+                    // The spacer bits in c make sure this isn't 0xff.
+                    self.buffer = Some(byte as u8);
+                } else if byte == 0xff {
+                    self.sc += 1;
+                } else {
+                    self.settle();
+                    self.buffer = Some(byte as u8);
+                }
+                self.c &= 0x7ffff;
+                self.ct += 8;
+            }
+            if self.a >= 0x8000 {
+                break;
+            }
+        }
+    }
+
+    // Writes out the rest of the coded data, with as few bytes as the
+    // decoder needs, and starts over.
+    fn flush(&mut self) {
+        // Pick the value in the interval with the most trailing zero bits.
+        let temp = (self.a - 1 + self.c) & 0xffff0000;
+        self.c = if temp < self.c { temp + 0x8000 } else { temp };
+        self.c <<= self.ct;
+        if self.c & 0xf8000000 != 0 {
+            self.carry();
+        } else {
+            self.settle();
+        }
+        // Trailing zero bytes are left out.
+        if self.c & 0x7fff800 != 0 {
+            self.emit_zeros();
+            self.emit((self.c >> 19) as u8);
+            if self.c & 0x7f800 != 0 {
+                self.emit((self.c >> 11) as u8);
+            }
+        }
+
+        self.c = 0;
+        self.a = 0x10000;
+        self.sc = 0;
+        self.zc = 0;
+        self.ct = 11;
+        self.buffer = None;
+    }
+}
+
+// Coding state of one scan, with the tables the frame gives each component.
+struct ArithCoder<'a, 'w> {
+    encoder: QmEncoder<'a, 'w>,
+    dc_stats: [[u8; DC_STAT_BINS]; 2],
+    ac_stats: [[u8; AC_STAT_BINS]; 2],
+    fixed: u8,
+    // Last DC value of each component, and the context its difference
+    // leaves for the next one.
+    pred: [i32; 4],
+    dc_context: [usize; 4],
+    // Tables of each component.
+    tables: [usize; 4],
+}
+
+impl<'a, 'w> ArithCoder<'a, 'w> {
+    fn new(out: &'a mut Writer<'w>, frame: &Frame) -> ArithCoder<'a, 'w> {
+        ArithCoder {
+            encoder: QmEncoder::new(out),
+            dc_stats: [[0; DC_STAT_BINS]; 2],
+            ac_stats: [[0; AC_STAT_BINS]; 2],
+            fixed: FIXED_STATE,
+            pred: [0; 4],
+            dc_context: [0; 4],
+            tables: frame.tables,
+        }
+    }
+}
+
+impl<'a, 'w> BlockCoder for ArithCoder<'a, 'w> {
+    fn dc_first(&mut self, du: &[i16; 64], c: usize, al: u8) {
+        let stats = &mut self.dc_stats[self.tables[c]];
+        let encoder = &mut self.encoder;
+        let val = (du[0] as i32) >> al;
+        let diff = val - self.pred[c];
+        let mut st = self.dc_context[c];
+        if diff == 0 {
+            encoder.encode(&mut stats[st], false);
+            self.dc_context[c] = 0;
+            return;
+        }
+        self.pred[c] = val;
+        encoder.encode(&mut stats[st], true);
+
+        // Sign
+        let v = if diff > 0 {
+            encoder.encode(&mut stats[st + 1], false);
+            st += 2;
+            self.dc_context[c] = 4;
+            diff - 1
+        } else {
+            encoder.encode(&mut stats[st + 1], true);
+            st += 3;
+            self.dc_context[c] = 8;
+            -diff - 1
+        };
+
+        // Magnitude category: the number of bits of |diff| - 1, in unary.
+        let mut m = 0;
+        if v != 0 {
+            encoder.encode(&mut stats[st], true);
+            m = 1;
+            st = 20;
+            let mut v2 = v >> 1;
+            while v2 != 0 {
+                encoder.encode(&mut stats[st], true);
+                m <<= 1;
+                st += 1;
+                v2 >>= 1;
+            }
+        }
+        encoder.encode(&mut stats[st], false);
+
+        // Small differences leave the next one in the zero context, and large
+        // ones in contexts of their own.
+        if m < (1 << DC_L) >> 1 {
+            self.dc_context[c] = 0;
+        } else if m > (1 << DC_U) >> 1 {
+            self.dc_context[c] += 8;
+        }
+
+        // Magnitude bits below the leading one.
+        st += 14;
+        m >>= 1;
+        while m != 0 {
+            encoder.encode(&mut stats[st], m & v != 0);
+            m >>= 1;
+        }
+    }
+
+    fn dc_refine(&mut self, du: &[i16; 64], al: u8) {
+        let bit = ((du[0] as i32) >> al) & 1;
+        self.encoder.encode(&mut self.fixed, bit != 0);
+    }
+
+    fn ac_first(&mut self, du: &[i16; 64], c: usize, scan: &Scan) {
+        let stats = &mut self.ac_stats[self.tables[c]];
+        let encoder = &mut self.encoder;
+        let (ss, se) = (scan.ss as usize, scan.se as usize);
+        // Drop the low bits of the magnitude, so values round towards zero.
+        let value = |k: usize| {
+            let coef = du[k] as i32;
+            if coef < 0 {
+                -(-coef >> scan.al)
+            } else {
+                coef >> scan.al
+            }
+        };
+        // Last nonzero coefficient; the block ends after it.
+        let eob = (ss..se + 1).rev().find(|&k| value(k) != 0).unwrap_or(0);
+
+        let mut k = ss;
+        while k <= eob {
+            let mut st = 3 * (k - 1);
+            // Not the end of the block.
+            encoder.encode(&mut stats[st], false);
+            // Zeros up to the next nonzero coefficient, then its sign.
+            let val = loop {
+                let val = value(k);
+                if val != 0 {
+                    encoder.encode(&mut stats[st + 1], true);
+                    encoder.encode(&mut self.fixed, val < 0);
+                    break val.abs();
+                }
+                encoder.encode(&mut stats[st + 1], false);
+                st += 3;
+                k += 1;
+            };
+
+            // Magnitude category of |val| - 1, in unary. Low and high
+            // frequencies have separate contexts from the third decision on.
+            st += 2;
+            let v = val - 1;
+            let mut m = 0;
+            if v != 0 {
+                encoder.encode(&mut stats[st], true);
+                m = 1;
+                let mut v2 = v >> 1;
+                if v2 != 0 {
+                    encoder.encode(&mut stats[st], true);
+                    m <<= 1;
+                    st = if k <= AC_K as usize { 189 } else { 217 };
+                    v2 >>= 1;
+                    while v2 != 0 {
+                        encoder.encode(&mut stats[st], true);
+                        m <<= 1;
+                        st += 1;
+                        v2 >>= 1;
+                    }
+                }
+            }
+            encoder.encode(&mut stats[st], false);
+
+            // Magnitude bits below the leading one.
+            st += 14;
+            m >>= 1;
+            while m != 0 {
+                encoder.encode(&mut stats[st], m & v != 0);
+                m >>= 1;
+            }
+            k += 1;
+        }
+        if k <= se {
+            encoder.encode(&mut stats[3 * (k - 1)], true);
+        }
+    }
+
+    fn ac_refine(&mut self, du: &[i16; 64], c: usize, scan: &Scan) {
+        let stats = &mut self.ac_stats[self.tables[c]];
+        let encoder = &mut self.encoder;
+        let (ss, se) = (scan.ss as usize, scan.se as usize);
+        let magnitude = |k: usize, shift: u8| (du[k] as i32).abs() >> shift;
+        // Last coefficient that is nonzero after this scan, and after the
+        // previous one.
+        let eob = (ss..se + 1)
+            .rev()
+            .find(|&k| magnitude(k, scan.al) != 0)
+            .unwrap_or(0);
+        let prev_eob = (ss..eob + 1)
+            .rev()
+            .find(|&k| magnitude(k, scan.ah) != 0)
+            .unwrap_or(0);
+
+        let mut k = ss;
+        while k <= eob {
+            let mut st = 3 * (k - 1);
+            // The end of the block is only possible past the previous one.
+            if k > prev_eob {
+                encoder.encode(&mut stats[st], false);
+            }
+            loop {
+                let val = magnitude(k, scan.al);
+                if val > 1 {
+                    // Already nonzero: the next bit.
+                    encoder.encode(&mut stats[st + 2], val & 1 != 0);
+                    break;
+                }
+                if val == 1 {
+                    // Newly nonzero: its sign.
+                    encoder.encode(&mut stats[st + 1], true);
+                    encoder.encode(&mut self.fixed, du[k] < 0);
+                    break;
+                }
+                encoder.encode(&mut stats[st + 1], false);
+                st += 3;
+                k += 1;
+            }
+            k += 1;
+        }
+        if k <= se {
+            encoder.encode(&mut stats[3 * (k - 1)], true);
+        }
+    }
+
+    fn restart(&mut self, _c: usize, n: usize) {
+        self.encoder.flush();
+        self.encoder.out.write_u16(0xffd0 + (n % 8) as u16);
+        self.dc_stats = [[0; DC_STAT_BINS]; 2];
+        self.ac_stats = [[0; AC_STAT_BINS]; 2];
+        self.pred = [0; 4];
+        self.dc_context = [0; 4];
+    }
+
+    fn finish(&mut self, _c: usize) {
+        self.encoder.flush();
+    }
+}
+
+// Writes the single scan of a sequential frame, coding every MCU as soon as
+// it is quantized.
+pub fn append_sequential_scan(
+    out: &mut Writer,
+    frame: &Frame,
+    source: &dyn BlockSource,
+    quant: &Quantizer,
+    restart_interval: usize,
+) {
+    let mcu_components = frame.mcu_components();
+    append_dac(out, frame.num_tables());
+    let scan_components: Vec<usize> = (0..frame.num_components).collect();
+    append_sos(out, frame, &scan_components, 0, 63, 0);
+
+    // A sequential block is coded as a first DC scan followed by a first AC
+    // scan over the rest of the coefficients, both without point transform.
+    let ac = Scan {
+        components: scan_components,
+        ss: 1,
+        se: 63,
+        ah: 0,
+        al: 0,
+    };
+    let mut coder = ArithCoder::new(out, frame);
+    let mut mcu_index = 0;
+    for_each_mcu(frame, source, 0..frame.mcu_rows(), quant, restart_interval, |mcu| {
+        if starts_restart_interval(mcu_index, restart_interval) {
+            coder.restart(0, mcu_index / restart_interval - 1);
+        }
+        mcu_index += 1;
+        for (du, &c) in mcu.iter().zip(mcu_components.iter()) {
+            coder.dc_first(du, c, 0);
+            coder.ac_first(du, c, &ac);
+        }
+    });
+    coder.finish(0);
+}
+
+// Writes the scans of a progressive frame.
+pub fn append_progressive_scans(
+    out: &mut Writer,
+    frame: &Frame,
+    coefficients: &[[i16; 64]],
+    restart_interval: usize,
+) {
+    append_dac(out, frame.num_tables());
+    for scan in default_scans(frame.num_components, frame.num_components == 3).iter() {
+        let ah_al = (scan.ah << 4) | scan.al;
+        append_sos(out, frame, &scan.components, scan.ss, scan.se, ah_al);
+        let mut coder = ArithCoder::new(out, frame);
+        code_scan(&mut coder, frame, coefficients, scan, restart_interval);
+    }
+}
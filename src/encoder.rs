@@ -0,0 +1,287 @@
+/* Encoding through a builder.
+
+   An `Encoder` takes the settings of `EncoderOptions` one typed call at a
+   time, then encodes any number of images with them, into a buffer, a
+   writer, a file or a stream of rows. The free functions of the crate are
+   shorthands for it.
+
+   Its tables are built again for each image. A `PreparedEncoder` builds
+   them once, with the quantizer and the SIMD kernels that use them, for
+   encoding many images of the same sample type, such as the frames of a
+   video, and can fill the same buffer with each of them. */
+
+use std::fs::File;
+use std::io::Write;
+use std::marker::PhantomData;
+use std::mem;
+use std::path::Path;
+
+use super::{
+    encode_blocks, encode_main, EncodeError, EncoderOptions, HuffmanTable, ImageView, Metadata,
+    PixelFormat, Predictor, Quality, QuantizationTable, Quantizer, Sample, State, StreamEncoder,
+    Subsampling, Writer,
+};
+
+/// How the quantized coefficients are coded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntropyCoding {
+    /// Huffman codes from the example tables of the JPEG spec, or from
+    /// `EncoderOptions::huffman_tables`.
+    Huffman,
+    /// Huffman codes from tables fitted to the image.
+    OptimizedHuffman,
+    /// The arithmetic coder, which gives the smallest files, but which not
+    /// every decoder supports.
+    Arithmetic,
+}
+
+/// Settings for encoding images, built up from the defaults of
+/// `EncoderOptions` one at a time, and the methods that encode with them.
+/// The size and pixel format of each image come with its `ImageView`.
+#[derive(Clone, Debug, Default)]
+pub struct Encoder {
+    options: EncoderOptions,
+}
+
+impl From<EncoderOptions> for Encoder {
+    fn from(options: EncoderOptions) -> Encoder {
+        Encoder { options }
+    }
+}
+
+impl Encoder {
+    /// Returns an encoder with the default settings: the highest quality, no
+    /// chroma subsampling, baseline Huffman coding and no restart markers.
+    pub fn new() -> Encoder {
+        Encoder::default()
+    }
+
+    pub fn quality(mut self, quality: Quality) -> Encoder {
+        self.options.quality = quality;
+        self
+    }
+
+    pub fn subsampling(mut self, subsampling: Subsampling) -> Encoder {
+        self.options.subsampling = subsampling;
+        self
+    }
+
+    /// Number of MCUs between restart markers, or 0 for none.
+    pub fn restart_interval(mut self, mcus: u16) -> Encoder {
+        self.options.restart_interval = mcus;
+        self
+    }
+
+    pub fn entropy_coding(mut self, coding: EntropyCoding) -> Encoder {
+        self.options.optimize_huffman = coding == EntropyCoding::OptimizedHuffman;
+        self.options.arithmetic_coding = coding == EntropyCoding::Arithmetic;
+        self
+    }
+
+    /// Whether to write a progressive JPEG. Its Huffman tables are always
+    /// fitted to the image.
+    pub fn progressive(mut self, progressive: bool) -> Encoder {
+        self.options.progressive = progressive;
+        self
+    }
+
+    pub fn metadata(mut self, metadata: Metadata) -> Encoder {
+        self.options.metadata = metadata;
+        self
+    }
+
+    /// Threads to code sequential Huffman-coded JPEGs on, as in
+    /// `EncoderOptions::threads`.
+    pub fn threads(mut self, threads: usize) -> Encoder {
+        self.options.threads = Some(threads);
+        self
+    }
+
+    /// Trellis quantization with the given lambda, which must be finite and
+    /// positive, as in `EncoderOptions::trellis`.
+    pub fn trellis(mut self, lambda: f32) -> Encoder {
+        self.options.trellis = Some(lambda);
+        self
+    }
+
+    /// Whether to convert, transform and quantize with integer arithmetic
+    /// only, as in `EncoderOptions::fixed_point`.
+    pub fn fixed_point(mut self, fixed_point: bool) -> Encoder {
+        self.options.fixed_point = fixed_point;
+        self
+    }
+
+    /// Writes lossless JPEGs with the given predictor, which most of the
+    /// other settings then have no effect on.
+    pub fn lossless(mut self, predictor: Predictor) -> Encoder {
+        self.options.lossless = Some(predictor);
+        self
+    }
+
+    /// Low bits dropped from every sample of a lossless JPEG.
+    pub fn point_transform(mut self, bits: u8) -> Encoder {
+        self.options.point_transform = bits;
+        self
+    }
+
+    /// Whether to store CMYK images as YCCK.
+    pub fn ycck(mut self, ycck: bool) -> Encoder {
+        self.options.ycck = ycck;
+        self
+    }
+
+    /// Quantization tables to use instead of the scaled example tables, and
+    /// the slot of each component, or None for the default slots.
+    pub fn quantization_tables(
+        mut self,
+        tables: Vec<QuantizationTable>,
+        slots: Option<[u8; 4]>,
+    ) -> Encoder {
+        self.options.quantization_tables = tables;
+        self.options.component_quantization = slots;
+        self
+    }
+
+    /// Huffman tables to use instead of the example tables, as DC and AC
+    /// pairs, and the slot of each component, or None for the default slots.
+    pub fn huffman_tables(
+        mut self,
+        tables: Vec<(HuffmanTable, HuffmanTable)>,
+        slots: Option<[u8; 4]>,
+    ) -> Encoder {
+        self.options.huffman_tables = tables;
+        self.options.component_huffman = slots;
+        self
+    }
+
+    /// Returns the settings.
+    pub fn options(&self) -> &EncoderOptions {
+        &self.options
+    }
+
+    /// Returns a JPEG-encoded buffer, given a view of bitmap data. `u16` data
+    /// gives a 12-bit JPEG.
+    pub fn encode<T: Sample>(&self, image: &ImageView<T>) -> Result<Vec<u8>, EncodeError> {
+        image.check()?;
+        encode_blocks(&self.options, image, None)
+    }
+
+    /// Writes a JPEG-encoded image to `sink` in chunks as it is encoded. The
+    /// output isn't held in memory as a whole, except for the bands of a scan
+    /// coded on several threads. Nothing is written if the image or the
+    /// settings are invalid. Returns the first error from the sink, after
+    /// which the image is left partly written.
+    pub fn encode_to_writer<W: Write, T: Sample>(
+        &self,
+        mut sink: W,
+        image: &ImageView<T>,
+    ) -> Result<(), EncodeError> {
+        image.check()?;
+        let mem = State::new(&self.options, T::PRECISION)?;
+        let quant = Quantizer::new(&mem, &self.options);
+        write_image(&mut sink, &mem, &quant, &self.options, image)
+    }
+
+    /// Writes a JPEG-encoded image to a file at `dest`, which is only created
+    /// if the image and the settings are valid.
+    pub fn encode_to_file<T: Sample>(
+        &self,
+        dest: &Path,
+        image: &ImageView<T>,
+    ) -> Result<(), EncodeError> {
+        image.check()?;
+        let mem = State::new(&self.options, T::PRECISION)?;
+        let quant = Quantizer::new(&mem, &self.options);
+        write_image(&mut File::create(dest)?, &mem, &quant, &self.options, image)
+    }
+
+    /// Writes the headers of a `width` x `height` image in `format` to
+    /// `sink`, and returns a `StreamEncoder` for its rows.
+    pub fn stream<W: Write, T: Sample>(
+        &self,
+        sink: W,
+        width: u32,
+        height: u32,
+        format: PixelFormat,
+    ) -> Result<StreamEncoder<W, T>, EncodeError> {
+        StreamEncoder::new(sink, &self.options, width, height, format)
+    }
+
+    /// Builds the quantization and Huffman tables for images of `T` samples,
+    /// and what quantizes with them, once, and returns an encoder that uses
+    /// them for every image. Fails if the settings are invalid for such
+    /// images.
+    pub fn prepare<T: Sample>(&self) -> Result<PreparedEncoder<T>, EncodeError> {
+        let mem = State::new(&self.options, T::PRECISION)?;
+        Ok(PreparedEncoder {
+            quant: Quantizer::new(&mem, &self.options),
+            mem,
+            options: self.options.clone(),
+            sample: PhantomData,
+        })
+    }
+}
+
+/// An encoder whose tables are built once, by `Encoder::prepare`, for
+/// encoding many images with the same settings. It can be shared by several
+/// threads, each encoding its own images.
+#[derive(Clone)]
+pub struct PreparedEncoder<T: Sample = u8> {
+    options: EncoderOptions,
+    mem: State,
+    quant: Quantizer,
+    // Only the type of the samples is kept, so the encoder is Send and Sync
+    // whatever they are.
+    sample: PhantomData<fn() -> T>,
+}
+
+impl<T: Sample> PreparedEncoder<T> {
+    pub fn options(&self) -> &EncoderOptions {
+        &self.options
+    }
+
+    /// Returns a JPEG-encoded buffer, given a view of bitmap data.
+    pub fn encode(&self, image: &ImageView<T>) -> Result<Vec<u8>, EncodeError> {
+        let mut out = vec![];
+        self.encode_into(image, &mut out)?;
+        Ok(out)
+    }
+
+    /// Replaces the contents of `out` with a JPEG-encoded image, given a view
+    /// of bitmap data. Once `out` has grown to the size of a frame, encoding
+    /// more frames into it doesn't allocate it again. `out` is left as it was
+    /// if the image is invalid.
+    pub fn encode_into(&self, image: &ImageView<T>, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        image.check()?;
+        let mut writer = Writer::reuse(mem::take(out));
+        encode_main(&mut writer, &self.mem, &self.quant, &self.options, image, None);
+        *out = writer.into_bytes();
+        Ok(())
+    }
+
+    /// Writes a JPEG-encoded image to `sink`, as `Encoder::encode_to_writer`
+    /// does.
+    pub fn encode_to_writer<W: Write>(
+        &self,
+        mut sink: W,
+        image: &ImageView<T>,
+    ) -> Result<(), EncodeError> {
+        image.check()?;
+        write_image(&mut sink, &self.mem, &self.quant, &self.options, image)
+    }
+}
+
+// Writes the image to `sink` in chunks, and flushes it.
+fn write_image<W: Write, T: Sample>(
+    sink: &mut W,
+    mem: &State,
+    quant: &Quantizer,
+    options: &EncoderOptions,
+    image: &ImageView<T>,
+) -> Result<(), EncodeError> {
+    let mut out = Writer::new(sink);
+    encode_main(&mut out, mem, quant, options, image, None);
+    out.finish()?;
+    sink.flush()?;
+    Ok(())
+}
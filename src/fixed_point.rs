@@ -0,0 +1,287 @@
+/* Fixed-point encoding.
+
+   The default pipeline converts colors, transforms and quantizes in floating
+   point, whose last bits can change with the CPU and the compiler flags. This
+   one only uses integer arithmetic, the way libjpeg does with JDCT_ISLOW:
+   color conversion with 16-bit fractions, rounded averages for downsampling,
+   libjpeg's accurate integer DCT, and rounded integer division for
+   quantization. It gives the same coefficients on every platform.
+
+   The coefficients that the DCT gives are integers of at most 19 bits, which
+   `f32` holds exactly, so they are passed on in the same blocks as those of
+   the float pipeline. */
+
+use super::{Pipeline, ZIG_ZAG};
+use simd::Kernels;
+
+// Color conversion constants of jccolor.c: the coefficients of the JFIF
+// conversion scaled by 2^16.
+pub const SCALE_BITS: i32 = 16;
+pub const ONE_HALF: i32 = 1 << (SCALE_BITS - 1);
+pub const FIX_0_29900: i32 = 19595;
+pub const FIX_0_58700: i32 = 38470;
+pub const FIX_0_11400: i32 = 7471;
+pub const FIX_0_16874: i32 = 11059;
+pub const FIX_0_33126: i32 = 21709;
+pub const FIX_0_50000: i32 = 32768;
+pub const FIX_0_41869: i32 = 27439;
+pub const FIX_0_08131: i32 = 5329;
+
+/* The fixed-point pipeline. Samples stay in their own range, 0 to 255 or
+   4095, until they are centered on zero just before the DCT. */
+pub struct FixedPoint {
+    kernels: Kernels,
+    precision: u8,
+    max: i32,
+    center: i32,
+}
+
+impl FixedPoint {
+    pub fn new(kernels: Kernels, precision: u8) -> FixedPoint {
+        FixedPoint {
+            kernels,
+            precision,
+            max: (1 << precision) - 1,
+            center: 1 << (precision - 1),
+        }
+    }
+}
+
+impl Pipeline for FixedPoint {
+    type Value = i32;
+
+    fn sample(&self, sample: u16, inverted: bool) -> i32 {
+        if inverted {
+            self.max - sample as i32
+        } else {
+            sample as i32
+        }
+    }
+
+    fn color(&self, sample: u16) -> i32 {
+        sample as i32
+    }
+
+    fn rgb_to_ycbcr(&self, planes: &mut [[i32; 256]; 4], len: usize) {
+        (self.kernels.rgb_to_ycbcr_fixed)(planes, len, self.center);
+    }
+
+    /* Like libjpeg, 2x1 and 2x2 averages round up and down in alternate
+       columns, so that they don't drift either way, and the others round to
+       the nearest. */
+    fn downsample(&self, du: &mut [i32; 64], plane: &[i32; 256], h_samp: usize, v_samp: usize) {
+        let mcu_w = 8 * h_samp;
+        let count = (h_samp * v_samp) as i32;
+        for off_y in 0..8 {
+            for off_x in 0..8 {
+                let bias = match (h_samp, v_samp) {
+                    (2, 1) => (off_x % 2) as i32,
+                    (2, 2) => 1 + (off_x % 2) as i32,
+                    _ => count / 2,
+                };
+                let mut sum = 0;
+                for sy in 0..v_samp {
+                    for sx in 0..h_samp {
+                        sum += plane[(off_y * v_samp + sy) * mcu_w + off_x * h_samp + sx];
+                    }
+                }
+                du[off_y * 8 + off_x] = (sum + bias) / count;
+            }
+        }
+    }
+
+    fn transform(&self, block: &mut [f32; 64], du: &mut [i32; 64]) {
+        for value in du.iter_mut() {
+            *value -= self.center;
+        }
+        (self.kernels.fdct_islow)(du, self.precision);
+        for (coef, &value) in block.iter_mut().zip(du.iter()) {
+            *coef = value as f32;
+        }
+    }
+}
+
+/* Converts the first `len` pixels of the first three planes from R, G and B
+   to Y, Cb and Cr, with Cb and Cr centered on `center`, as jccolor.c does. */
+#[allow(clippy::needless_range_loop)]
+pub fn rgb_to_ycbcr(planes: &mut [[i32; 256]; 4], len: usize, center: i32) {
+    /* Cb and Cr are offset by half the range. Rounding them with
+       ONE_HALF - 1 keeps the largest value in range. */
+    let chroma_offset = (center << SCALE_BITS) + ONE_HALF - 1;
+    for i in 0..len {
+        let r = planes[0][i];
+        let g = planes[1][i];
+        let b = planes[2][i];
+        planes[0][i] =
+            (FIX_0_29900 * r + FIX_0_58700 * g + FIX_0_11400 * b + ONE_HALF) >> SCALE_BITS;
+        planes[1][i] =
+            (-FIX_0_16874 * r - FIX_0_33126 * g + FIX_0_50000 * b + chroma_offset) >> SCALE_BITS;
+        planes[2][i] =
+            (FIX_0_50000 * r - FIX_0_41869 * g - FIX_0_08131 * b + chroma_offset) >> SCALE_BITS;
+    }
+}
+
+/* Quantizes a block of integer DCT coefficients in natural order by the
+   `divisors` of its table, also in natural order, rounding halves away from
+   zero as libjpeg does. Writes the result to `du` in zigzag order. */
+pub fn quantize(du: &mut [i16; 64], dct_mcu: &[f32; 64], divisors: &[i32; 64]) {
+    for i in 0..64 {
+        let coef = dct_mcu[i] as i32;
+        let divisor = divisors[i];
+        let value = if coef < 0 {
+            -((divisor / 2 - coef) / divisor)
+        } else {
+            (coef + divisor / 2) / divisor
+        };
+        du[ZIG_ZAG[i]] = value as i16;
+    }
+}
+
+// Constants of jfdctint.c: cosines scaled by 2^13.
+pub const CONST_BITS: i64 = 13;
+pub const FIX_0_298631336: i64 = 2446;
+pub const FIX_0_390180644: i64 = 3196;
+pub const FIX_0_541196100: i64 = 4433;
+pub const FIX_0_765366865: i64 = 6270;
+pub const FIX_0_899976223: i64 = 7373;
+pub const FIX_1_175875602: i64 = 9633;
+pub const FIX_1_501321110: i64 = 12299;
+pub const FIX_1_847759065: i64 = 15137;
+pub const FIX_1_961570560: i64 = 16069;
+pub const FIX_2_053119869: i64 = 16819;
+pub const FIX_2_562915447: i64 = 20995;
+pub const FIX_3_072711336: i64 = 25172;
+
+// Shifts `x` right by `n` bits, rounding to the nearest.
+fn descale(x: i64, n: i64) -> i64 {
+    (x + (1 << (n - 1))) >> n
+}
+
+/* Accurate integer forward DCT, jpeg_fdct_islow of libjpeg's jfdctint.c by
+   Thomas G. Lane.
+
+   This is the Loeffler, Ligtenberg and Moschytz algorithm, with 12
+   multiplies and 32 adds per pass, scaled by 2^13 fixed-point constants. The
+   first pass keeps PASS1_BITS more bits than the samples, two, or one for
+   12-bit samples. The products of 12-bit samples can pass 32 bits, which
+   libjpeg's INT32, a C long, holds on 64-bit platforms, so the arithmetic is
+   64-bit here, between 32-bit samples and coefficients. The outputs are
+   scaled up by 8, like those of the float `fdct` before its quantization
+   tables divide it out. */
+#[allow(clippy::erasing_op, clippy::identity_op)]
+pub fn fdct_islow(block: &mut [i32; 64], precision: u8) {
+    let mut data = [0i64; 64];
+    for i in 0..64 {
+        data[i] = block[i] as i64;
+    }
+
+    let pass1_bits = if precision > 8 { 1 } else { 2 };
+    let (mut tmp0, mut tmp1, mut tmp2, mut tmp3, mut tmp4, mut tmp5, mut tmp6,
+         mut tmp7, mut tmp10, mut tmp11, mut tmp12, mut tmp13);
+    let (mut z1, mut z2, mut z3, mut z4, mut z5);
+
+    /* Pass 1: process rows. Results are scaled up by 2^pass1_bits. */
+
+    for i in (0..64).step_by(8) {
+        tmp0 = data[i + 0] + data[i + 7];
+        tmp7 = data[i + 0] - data[i + 7];
+        tmp1 = data[i + 1] + data[i + 6];
+        tmp6 = data[i + 1] - data[i + 6];
+        tmp2 = data[i + 2] + data[i + 5];
+        tmp5 = data[i + 2] - data[i + 5];
+        tmp3 = data[i + 3] + data[i + 4];
+        tmp4 = data[i + 3] - data[i + 4];
+
+        // Even part
+        tmp10 = tmp0 + tmp3;
+        tmp13 = tmp0 - tmp3;
+        tmp11 = tmp1 + tmp2;
+        tmp12 = tmp1 - tmp2;
+
+        data[i + 0] = (tmp10 + tmp11) << pass1_bits;
+        data[i + 4] = (tmp10 - tmp11) << pass1_bits;
+
+        z1 = (tmp12 + tmp13) * FIX_0_541196100;
+        data[i + 2] = descale(z1 + tmp13 * FIX_0_765366865, CONST_BITS - pass1_bits);
+        data[i + 6] = descale(z1 - tmp12 * FIX_1_847759065, CONST_BITS - pass1_bits);
+
+        // Odd part
+        z1 = tmp4 + tmp7;
+        z2 = tmp5 + tmp6;
+        z3 = tmp4 + tmp6;
+        z4 = tmp5 + tmp7;
+        z5 = (z3 + z4) * FIX_1_175875602; // sqrt(2) * c3
+
+        tmp4 *= FIX_0_298631336; // sqrt(2) * (-c1+c3+c5-c7)
+        tmp5 *= FIX_2_053119869; // sqrt(2) * ( c1+c3-c5+c7)
+        tmp6 *= FIX_3_072711336; // sqrt(2) * ( c1+c3+c5-c7)
+        tmp7 *= FIX_1_501321110; // sqrt(2) * ( c1+c3-c5-c7)
+        z1 *= -FIX_0_899976223; // sqrt(2) * (c7-c3)
+        z2 *= -FIX_2_562915447; // sqrt(2) * (-c1-c3)
+        z3 *= -FIX_1_961570560; // sqrt(2) * (-c3-c5)
+        z4 *= -FIX_0_390180644; // sqrt(2) * (c5-c3)
+
+        z3 += z5;
+        z4 += z5;
+
+        data[i + 7] = descale(tmp4 + z1 + z3, CONST_BITS - pass1_bits);
+        data[i + 5] = descale(tmp5 + z2 + z4, CONST_BITS - pass1_bits);
+        data[i + 3] = descale(tmp6 + z2 + z3, CONST_BITS - pass1_bits);
+        data[i + 1] = descale(tmp7 + z1 + z4, CONST_BITS - pass1_bits);
+    }
+
+    /* Pass 2: process columns. The pass1_bits scaling is removed, leaving
+       the results scaled up by 8. */
+
+    for i in 0..8 {
+        tmp0 = data[i + 8 * 0] + data[i + 8 * 7];
+        tmp7 = data[i + 8 * 0] - data[i + 8 * 7];
+        tmp1 = data[i + 8 * 1] + data[i + 8 * 6];
+        tmp6 = data[i + 8 * 1] - data[i + 8 * 6];
+        tmp2 = data[i + 8 * 2] + data[i + 8 * 5];
+        tmp5 = data[i + 8 * 2] - data[i + 8 * 5];
+        tmp3 = data[i + 8 * 3] + data[i + 8 * 4];
+        tmp4 = data[i + 8 * 3] - data[i + 8 * 4];
+
+        // Even part
+        tmp10 = tmp0 + tmp3;
+        tmp13 = tmp0 - tmp3;
+        tmp11 = tmp1 + tmp2;
+        tmp12 = tmp1 - tmp2;
+
+        data[i + 8 * 0] = descale(tmp10 + tmp11, pass1_bits);
+        data[i + 8 * 4] = descale(tmp10 - tmp11, pass1_bits);
+
+        z1 = (tmp12 + tmp13) * FIX_0_541196100;
+        data[i + 8 * 2] = descale(z1 + tmp13 * FIX_0_765366865, CONST_BITS + pass1_bits);
+        data[i + 8 * 6] = descale(z1 - tmp12 * FIX_1_847759065, CONST_BITS + pass1_bits);
+
+        // Odd part
+        z1 = tmp4 + tmp7;
+        z2 = tmp5 + tmp6;
+        z3 = tmp4 + tmp6;
+        z4 = tmp5 + tmp7;
+        z5 = (z3 + z4) * FIX_1_175875602;
+
+        tmp4 *= FIX_0_298631336;
+        tmp5 *= FIX_2_053119869;
+        tmp6 *= FIX_3_072711336;
+        tmp7 *= FIX_1_501321110;
+        z1 *= -FIX_0_899976223;
+        z2 *= -FIX_2_562915447;
+        z3 *= -FIX_1_961570560;
+        z4 *= -FIX_0_390180644;
+
+        z3 += z5;
+        z4 += z5;
+
+        data[i + 8 * 7] = descale(tmp4 + z1 + z3, CONST_BITS + pass1_bits);
+        data[i + 8 * 5] = descale(tmp5 + z2 + z4, CONST_BITS + pass1_bits);
+        data[i + 8 * 3] = descale(tmp6 + z2 + z3, CONST_BITS + pass1_bits);
+        data[i + 8 * 1] = descale(tmp7 + z1 + z4, CONST_BITS + pass1_bits);
+    }
+
+    for i in 0..64 {
+        block[i] = data[i] as i32;
+    }
+}
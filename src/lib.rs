@@ -1,22 +1,44 @@
-extern crate byteorder;
-
-use byteorder::{BigEndian, WriteBytesExt};
+use std::error;
+use std::fmt;
 use std::io;
+use std::ops::Range;
 use std::io::Write;
-use std::fs::File;
 use std::path::Path;
+use std::thread;
+
+mod arithmetic;
+mod encoder;
+mod fixed_point;
+mod lossless;
+mod metric;
+mod progressive;
+mod simd;
+mod stream;
+mod trellis;
+mod writer;
+
+pub use encoder::{Encoder, EntropyCoding, PreparedEncoder};
+pub use stream::StreamEncoder;
+use writer::Writer;
 
 const QT_SIZE: usize = 64;
 
+#[derive(Clone)]
 struct State {
-    // Huffman data
-    ehuffsize: [[u8; 257]; 4],
-    ehuffcode: [[u16; 256]; 4],
-    ht_bits: [&'static [u8]; 4],
-    ht_vals: [&'static [u8]; 4],
-    // Quantization tables
-    qt_luma: [u8; QT_SIZE],
-    qt_chroma: [u8; QT_SIZE],
+    // Huffman data, a DC and an AC table per slot
+    ehuffsize: Vec<[u8; 257]>,
+    ehuffcode: Vec<[u16; 256]>,
+    ht_bits: Vec<[u8; 16]>,
+    ht_vals: Vec<Vec<u8>>,
+    // Quantization tables in zigzag order, by DQT slot
+    qt: Vec<[u16; QT_SIZE]>,
+    // Reciprocals of the quantization steps of each table slot, scaled for
+    // the AA&N DCT, in natural order. Fixed-point frames have the
+    // reciprocals of `divisors` instead, for the trellis.
+    pqt: Vec<[f32; 64]>,
+    // Quantization steps of each table slot scaled for the integer DCT, in
+    // natural order, for fixed-point frames.
+    divisors: Vec<[i32; 64]>,
 }
 
 const DEFAULT_QT_LUMA_FROM_SPEC: [u8; QT_SIZE] = [
@@ -30,15 +52,15 @@ const DEFAULT_QT_LUMA_FROM_SPEC: [u8; QT_SIZE] = [
     72, 92, 95, 98, 112, 100, 103, 99,
 ];
 
-const DETAULT_QT_CHROMA_FROM_PAPER: [u8; QT_SIZE] = [
-    16, 12, 14, 14, 18, 24, 49, 72,
-    11, 10, 16, 24, 40, 51, 61, 12,
-    13, 17, 22, 35, 64, 92, 14, 16,
-    22, 37, 55, 78, 95, 19, 24, 29,
-    56, 64, 87, 98, 26, 40, 51, 68,
-    81, 103, 112, 58, 57, 87, 109, 104,
-    121, 100, 60, 69, 80, 103, 113, 120,
-    103, 55, 56, 62, 77, 92, 101, 99,
+const DEFAULT_QT_CHROMA_FROM_SPEC: [u8; QT_SIZE] = [
+    17, 18, 24, 47, 99, 99, 99, 99,
+    18, 21, 26, 66, 99, 99, 99, 99,
+    24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
 ];
 
 const DEFAULT_HT_LUMA_DC_LEN: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
@@ -87,17 +109,48 @@ const ZIG_ZAG: [usize; 64] = [
     35, 36, 48, 49, 57, 58, 62, 63,
 ];
 
-fn append_dqt(out: &mut Vec<u8>, matrix: &[u8], id: u8) {
-    out.write_u16::<BigEndian>(0xffdb).unwrap();
-    out.write_u16::<BigEndian>(0x0043).unwrap(); // 2(len) + 1(id) + 64(matrix) = 67 = 0x43
+/// Scales a quantization table for a quality between 1 and 100, the same way
+/// libjpeg's `jpeg_quality_scaling` does. Quality 50 leaves the table as is and
+/// quality 100 produces an all-ones table. Values are limited to `max`, which
+/// is 255 for 8-bit tables and 32767 for 16-bit ones.
+fn scale_qt(out: &mut [u16; QT_SIZE], table: &[u8; QT_SIZE], quality: u8, max: u16) {
+    let quality = quality.clamp(1, 100) as u32;
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - quality * 2
+    };
+    for i in 0..QT_SIZE {
+        let val = (table[i] as u32 * scale + 50) / 100;
+        out[i] = val.clamp(1, max as u32) as u16;
+    }
+}
+
+// Writes a quantization table, with 16-bit entries for frames of more than 8
+// bits per sample and for tables with entries past 255.
+fn append_dqt(out: &mut Writer, matrix: &[u16], id: u8, precision: u8) {
+    out.write_u16(0xffdb);
     debug_assert!(id < 4);
-    out.push(id);
-    out.extend_from_slice(matrix);
+    if precision > 8 || matrix.iter().any(|&q| q > 0xff) {
+        out.write_u16(2 + 1 + 128);
+        out.push(0x10 | id);
+        for &q in matrix {
+            out.write_u16(q);
+        }
+    } else {
+        out.write_u16(0x0043); // 2(len) + 1(id) + 64(matrix) = 67 = 0x43
+        out.push(id);
+        for &q in matrix {
+            debug_assert!(q <= 0xff);
+            out.push(q as u8);
+        }
+    }
 }
 
-fn append_dht(out: &mut Vec<u8>, matrix_len: &[u8], matrix_val: &[u8], ht_class: i32, id: u8) {
+#[allow(clippy::needless_range_loop)]
+fn append_dht(out: &mut Writer, matrix_len: &[u8], matrix_val: &[u8], ht_class: i32, id: u8) {
     // DHT
-    out.write_u16::<BigEndian>(0xffc4).unwrap();
+    out.write_u16(0xffc4);
 
     // 2(len) + 1(Tc|th) + 16 (num lengths) + ?? (num values)
     let mut num_values = 0usize;
@@ -106,7 +159,7 @@ fn append_dht(out: &mut Vec<u8>, matrix_len: &[u8], matrix_val: &[u8], ht_class:
     }
     debug_assert!(num_values <= 0xffff);
     let len: u16 = 2 + 1 + 16 + num_values as u16;
-    out.write_u16::<BigEndian>(len).unwrap();
+    out.write_u16(len);
 
     // tc_th
     debug_assert!(id < 4);
@@ -117,6 +170,72 @@ fn append_dht(out: &mut Vec<u8>, matrix_len: &[u8], matrix_val: &[u8], ht_class:
     out.extend_from_slice(matrix_val);
 }
 
+// Writes an Adobe APP14 segment. `transform` is 0 for RGB or CMYK components,
+// 1 for YCbCr and 2 for YCCK. Four-component frames with this segment are
+// taken to be inverted, with 0 for full ink.
+fn append_adobe(out: &mut Writer, transform: u8) {
+    // APP14
+    out.write_u16(0xffee);
+    // Len
+    out.write_u16(14);
+    out.extend_from_slice(b"Adobe");
+    // Version, then two words of flags
+    out.write_u16(100);
+    out.write_u16(0);
+    out.write_u16(0);
+    out.push(transform);
+}
+
+// Writes a scan header for `components` of `frame`, coding coefficients `ss`
+// to `se` with successive approximation bits `ah_al`.
+fn append_sos(
+    out: &mut Writer,
+    frame: &Frame,
+    components: &[usize],
+    ss: u8,
+    se: u8,
+    ah_al: u8,
+) {
+    let mut tables = [0u8; 4];
+    for (table, &t) in tables.iter_mut().zip(frame.huff_tables.iter()).take(frame.num_components) {
+        *table = t as u8 * 0x11;
+    }
+    append_sos_with_tables(out, components, &tables, ss, se, ah_al);
+}
+
+// Same as `append_sos`, with the DC and AC table of each component in
+// `tables`.
+fn append_sos_with_tables(
+    out: &mut Writer,
+    components: &[usize],
+    tables: &[u8; 4],
+    ss: u8,
+    se: u8,
+    ah_al: u8,
+) {
+    // SOS
+    out.write_u16(0xffda);
+    // Length = 6 + (frame component spec * 2)
+    out.write_u16(6 + 2 * components.len() as u16);
+    // Number of components
+    out.push(components.len() as u8);
+
+    for &c in components {
+        /* Component ID -  Must be equal to component_id from frame header
+           above. */
+        out.push(c as u8 + 1);
+        // dc_ac
+        out.push(tables[c]);
+    }
+    // First
+    out.push(ss);
+    // Last
+    out.push(se);
+    // ah_al
+    out.push(ah_al);
+}
+
+#[allow(clippy::needless_range_loop)]
 fn huff_get_code_lengths(huffsize: &mut [u8], bits: &[u8]) {
     let mut k = 0;
     for i in 0..16 {
@@ -147,7 +266,7 @@ fn huff_get_codes(codes: &mut [u16], huffsize: &[u8], count: usize) {
             return;
         }
         loop {
-            code = code << 1;
+            code <<= 1;
             sz += 1;
             if huffsize[k] == sz {
                 break;
@@ -186,7 +305,7 @@ fn calculate_variable_length_int(mut val: i32) -> (u16, u16) {
 
     let mut num_bits = 1;
     loop {
-        abs_val = abs_val >> 1;
+        abs_val >>= 1;
         if abs_val == 0 {
             break;
         }
@@ -199,7 +318,7 @@ fn calculate_variable_length_int(mut val: i32) -> (u16, u16) {
 }
 
 fn append_bits(
-    out: &mut Vec<u8>,
+    out: &mut Writer,
     bitbuffer: &mut u32,
     location: &mut u32,
     num_bits: u16,
@@ -216,7 +335,7 @@ fn append_bits(
 
     // Push the stack.
     let nloc = *location + num_bits as u32;
-    *bitbuffer |= ((bits as u32) << (32 - nloc)) as u32;
+    *bitbuffer |= (bits as u32) << (32 - nloc);
     *location = nloc;
     while *location >= 8 {
         // Grab the most significant byte.
@@ -233,7 +352,33 @@ fn append_bits(
     }
 }
 
-fn fdct(data: &mut [f32]) {
+// Pads the last byte of entropy-coded data with zeros and writes it out.
+fn flush_bits(out: &mut Writer, bitbuffer: &mut u32, location: &mut u32) {
+    if *location > 0 && *location < 8 {
+        let num_bits = (8 - *location) as u16;
+        append_bits(out, bitbuffer, location, num_bits, 0);
+    }
+}
+
+// Whether MCU `mcu_index` starts a new restart interval.
+fn starts_restart_interval(mcu_index: usize, restart_interval: usize) -> bool {
+    restart_interval > 0 && mcu_index > 0 && mcu_index % restart_interval == 0
+}
+
+// Ends a restart interval: pads the entropy-coded data to a byte boundary and
+// writes marker RSTn, with n going from 0 to 7 and around again.
+fn append_restart(out: &mut Writer, bitbuffer: &mut u32, location: &mut u32, n: usize) {
+    flush_bits(out, bitbuffer, location);
+    out.write_u16(0xffd0 + (n % 8) as u16);
+}
+
+#[allow(
+    clippy::approx_constant,
+    clippy::erasing_op,
+    clippy::excessive_precision,
+    clippy::identity_op
+)]
+fn fdct(data: &mut [f32; 64]) {
     let (mut tmp0, mut tmp1, mut tmp2, mut tmp3, mut tmp4, mut tmp5, mut tmp6,
          mut tmp7, mut tmp10, mut tmp11, mut tmp12, mut tmp13);
     let (mut z1, mut z2, mut z3, mut z4, mut z5, mut z11, mut z13);
@@ -346,10 +491,50 @@ fn fdct(data: &mut [f32]) {
    textbook (see REFERENCES section in file README).  The following code is
    based directly on figure 4-8 in P&M. */
 
+// Divides every coefficient of a transformed block by its quantization step,
+// leaving the result in zigzag order.
+fn divide_du(coefs: &mut [f32; 64], dct_mcu: &[f32; 64], qt: &[f32]) {
+    for i in 0..64 {
+        coefs[ZIG_ZAG[i]] = dct_mcu[i] * qt[i];
+    }
+}
+
+// Quantizes one transformed block, writing the coefficients to `du` in zigzag
+// order.
+fn quantize_du(du: &mut [i16; 64], dct_mcu: &[f32; 64], qt: &[f32; 64]) {
+    let mut coefs = [0f32; 64];
+    divide_du(&mut coefs, dct_mcu, qt);
+    for i in 0..64 {
+        let mut fval = coefs[i];
+        fval = (fval + 1024.0 + 0.5).floor();
+        fval -= 1024.0;
+        du[i] = fval as i16;
+    }
+}
+
+// Converts the first `len` pixels of the first three planes from R, G and B
+// to Y, Cb and Cr, with luma centered on zero like the chroma.
+#[allow(clippy::needless_range_loop)]
+fn rgb_to_ycbcr(planes: &mut [[f32; 256]; 4], len: usize, level_shift: f32) {
+    for i in 0..len {
+        let r = planes[0][i];
+        let g = planes[1][i];
+        let b = planes[2][i];
+
+        let luma: f32 = 0.299 * r + 0.587 * g + 0.114 * b - level_shift;
+        let cb: f32 = -0.1687 * r - 0.3313 * g + 0.5 * b;
+        let cr: f32 = 0.5 * r - 0.4187 * g - 0.0813 * b;
+
+        planes[0][i] = luma;
+        planes[1][i] = cb;
+        planes[2][i] = cr;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn encode_and_append_mcu(
-    out: &mut Vec<u8>,
-    mcu: &[f32],
-    qt: &[f32],
+    out: &mut Writer,
+    du: &[i16; 64],
     huff_dc_len: &[u8],
     huff_dc_code: &[u16],
     huff_ac_len: &[u8],
@@ -358,22 +543,9 @@ fn encode_and_append_mcu(
     bitbuffer: &mut u32,
     location: &mut u32,
 ) {
-    let mut du = [0i32; 64];
-    let mut dct_mcu = [0f32; 64];
-    dct_mcu.copy_from_slice(mcu);
-    fdct(&mut dct_mcu);
-    for i in 0..64 {
-        let mut fval = dct_mcu[i];
-        fval *= qt[i];
-        fval = (fval + 1024.0 + 0.5).floor();
-        fval -= 1024.0;
-        let val = fval as i32;
-        du[ZIG_ZAG[i]] = val;
-    }
-
     // Encode DC coefficient.
-    let diff = du[0] - *pred;
-    *pred = du[0];
+    let diff = du[0] as i32 - *pred;
+    *pred = du[0] as i32;
     if diff != 0 {
         let (bits, num_bits) = calculate_variable_length_int(diff);
         // Write number of bits with Huffman coding
@@ -424,10 +596,10 @@ fn encode_and_append_mcu(
                 zero_count = 0;
             }
         }
-        let (bits, num_bits) = calculate_variable_length_int(du[i]);
+        let (bits, num_bits) = calculate_variable_length_int(du[i] as i32);
 
         debug_assert!(zero_count < 0x10);
-        debug_assert!(num_bits <= 10);
+        debug_assert!(num_bits <= 14);
 
         let sym1 = (((zero_count as u16) << 4) | num_bits) as usize;
 
@@ -458,366 +630,3373 @@ fn encode_and_append_mcu(
     }
 }
 
-fn huff_expand(mem: &mut State) {
-    // How many codes in total for each of LUMA_(DC|AC) and CHROMA_(DC|AC)
-    let mut spec_tables_len = [0usize; 4];
-    for i in 0..4 {
-        for k in 0..16 {
-            spec_tables_len[i] += mem.ht_bits[i][k] as usize;
+// Counts the Huffman symbols that `encode_and_append_mcu` writes for `du`.
+fn gather_du_stats(
+    du: &[i16; 64],
+    pred: &mut i32,
+    dc_freq: &mut [u32; 257],
+    ac_freq: &mut [u32; 257],
+) {
+    let diff = du[0] as i32 - *pred;
+    *pred = du[0] as i32;
+    if diff != 0 {
+        let (_, num_bits) = calculate_variable_length_int(diff);
+        dc_freq[num_bits as usize] += 1;
+    } else {
+        dc_freq[0] += 1;
+    }
+
+    let mut last_non_zero_i = 0;
+    for i in (0..64).rev() {
+        if du[i] != 0 {
+            last_non_zero_i = i;
+            break;
         }
     }
 
-    // Fill out the extended tables..
-    let mut huffsize = [[0u8; 257]; 4];
-    let mut huffcode = [[0u16; 256]; 4];
-    for i in 0..4 {
-        debug_assert!(256 >= spec_tables_len[i]);
-        huff_get_code_lengths(&mut huffsize[i], mem.ht_bits[i]);
-        huff_get_codes(&mut huffcode[i], &mut huffsize[i], spec_tables_len[i])
-    }
-    for i in 0..4 {
-        huff_get_extended(
+    let mut zero_count = 0;
+    for &coef in du[1..last_non_zero_i + 1].iter() {
+        if coef == 0 {
+            zero_count += 1;
+            continue;
+        }
+        while zero_count >= 16 {
+            ac_freq[0xf0] += 1;
+            zero_count -= 16;
+        }
+        let (_, num_bits) = calculate_variable_length_int(coef as i32);
+        ac_freq[(zero_count << 4) | num_bits as usize] += 1;
+        zero_count = 0;
+    }
+
+    if last_non_zero_i != 63 {
+        ac_freq[0] += 1;
+    }
+}
+
+/* Builds a Huffman table for the symbol counts in `freq`, with no code longer
+   than 16 bits and no code made of all ones. Returns the number of codes of
+   each length and the symbols sorted by code length, as in a DHT segment.
+
+   This follows section K.2 of the JPEG spec, as implemented by
+   jpeg_gen_optimal_table in libjpeg. Entry 256 of `freq` is a reserved
+   symbol; it gets a code of its own, which is then removed, so that no real
+   symbol ends up with the all-ones code. */
+#[allow(clippy::needless_range_loop)]
+fn huff_optimal_table(freq: &[u32; 257]) -> ([u8; 16], Vec<u8>) {
+    let mut freq = *freq;
+    freq[256] = 1;
+    let mut codesize = [0usize; 257];
+    // Next symbol in the same branch of the tree, or none.
+    let mut others = [None; 257];
+
+    loop {
+        // Find the least frequent symbol, breaking ties with the largest
+        // value, and then the next least frequent one.
+        let mut c1 = None;
+        let mut v = u32::MAX;
+        for i in 0..257 {
+            if freq[i] != 0 && freq[i] <= v {
+                v = freq[i];
+                c1 = Some(i);
+            }
+        }
+        let mut c2 = None;
+        v = u32::MAX;
+        for i in 0..257 {
+            if freq[i] != 0 && freq[i] <= v && Some(i) != c1 {
+                v = freq[i];
+                c2 = Some(i);
+            }
+        }
+        let (mut c1, mut c2) = match (c1, c2) {
+            (Some(c1), Some(c2)) => (c1, c2),
+            // Done once everything is merged into one tree.
+            _ => break,
+        };
+
+        // Merge the two trees.
+        freq[c1] += freq[c2];
+        freq[c2] = 0;
+        codesize[c1] += 1;
+        while let Some(next) = others[c1] {
+            c1 = next;
+            codesize[c1] += 1;
+        }
+        others[c1] = Some(c2);
+        codesize[c2] += 1;
+        while let Some(next) = others[c2] {
+            c2 = next;
+            codesize[c2] += 1;
+        }
+    }
+
+    // Count the codes of each length. The longest possible code is 256 bits
+    // in theory, but with 32-bit counts it is at most 32.
+    let mut bits = [0u8; 33];
+    for &size in codesize.iter() {
+        if size != 0 {
+            debug_assert!(size <= 32);
+            bits[size] += 1;
+        }
+    }
+
+    // Shorten codes longer than 16 bits. Each step takes two codes of the
+    // longest length, moves one of them up a level and turns a shorter code
+    // into the prefix of the other.
+    for i in (17..33).rev() {
+        while bits[i] > 0 {
+            let mut j = i - 2;
+            while bits[j] == 0 {
+                j -= 1;
+            }
+            bits[i] -= 2;
+            bits[i - 1] += 1;
+            bits[j + 1] += 2;
+            bits[j] -= 1;
+        }
+    }
+
+    // Remove the reserved symbol, which has the longest code.
+    let mut i = 16;
+    while bits[i] == 0 {
+        i -= 1;
+    }
+    bits[i] -= 1;
+
+    let mut ht_bits = [0u8; 16];
+    ht_bits.copy_from_slice(&bits[1..17]);
+    let mut ht_vals = vec![];
+    for size in 1..33 {
+        for symbol in 0..256 {
+            if codesize[symbol] == size {
+                ht_vals.push(symbol as u8);
+            }
+        }
+    }
+    (ht_bits, ht_vals)
+}
+
+/// Averages `h_samp` x `v_samp` pixels of an MCU into each sample of an 8x8
+/// block.
+fn downsample(du: &mut [f32; 64], mcu: &[f32], h_samp: usize, v_samp: usize) {
+    let mcu_w = 8 * h_samp;
+    let scale = 1.0 / (h_samp * v_samp) as f32;
+    for off_y in 0..8 {
+        for off_x in 0..8 {
+            let mut sum = 0.0;
+            for sy in 0..v_samp {
+                for sx in 0..h_samp {
+                    sum += mcu[(off_y * v_samp + sy) * mcu_w + off_x * h_samp + sx];
+                }
+            }
+            du[off_y * 8 + off_x] = sum * scale;
+        }
+    }
+}
+
+fn huff_expand(mem: &mut State) {
+    let num_tables = mem.ht_bits.len();
+    mem.ehuffsize = vec![[0u8; 257]; num_tables];
+    mem.ehuffcode = vec![[0u16; 256]; num_tables];
+    for i in 0..num_tables {
+        // Optimized tables of slots that no component uses have no codes.
+        if mem.ht_vals[i].is_empty() {
+            continue;
+        }
+        huff_expand_table(
+            &mem.ht_bits[i],
+            &mem.ht_vals[i],
             &mut mem.ehuffsize[i],
             &mut mem.ehuffcode[i],
-            &mem.ht_vals[i],
-            &huffsize[i],
-            &huffcode[i],
-            spec_tables_len[i],
         );
     }
 }
 
-fn encode_main(mem: &State, w: i32, h: i32, num_components: i32, data: &[u8]) -> Vec<u8> {
-    assert!(num_components == 3 || num_components == 4);
-    assert!(w <= 0xffff && h <= 0xffff);
+// Fills out the code size and code of every symbol of one Huffman table.
+#[allow(clippy::needless_range_loop)]
+fn huff_expand_table(
+    ht_bits: &[u8; 16],
+    ht_vals: &[u8],
+    ehuffsize: &mut [u8; 257],
+    ehuffcode: &mut [u16; 256],
+) {
+    // How many codes in total
+    let mut spec_table_len = 0usize;
+    for k in 0..16 {
+        spec_table_len += ht_bits[k] as usize;
+    }
+    debug_assert!(256 >= spec_table_len);
 
-    let mut pqt_chroma = [0f32; 64];
-    let mut pqt_luma = [0f32; 64];
+    // Fill out the extended tables..
+    *ehuffsize = [0u8; 257];
+    *ehuffcode = [0u16; 256];
+    let mut huffsize = [0u8; 257];
+    let mut huffcode = [0u16; 256];
+    huff_get_code_lengths(&mut huffsize, ht_bits);
+    huff_get_codes(&mut huffcode, &huffsize, spec_table_len);
+    huff_get_extended(
+        ehuffsize,
+        ehuffcode,
+        ht_vals,
+        &huffsize,
+        &huffcode,
+        spec_table_len,
+    );
+}
 
-    /* For float AA&N IDCT method, divisors are equal to quantization
-       coefficients scaled by scalefactor[row]*scalefactor[col], where
-         scalefactor[0] = 1
-         scalefactor[k] = cos(k*PI/16) * sqrt(2)    for k=1..7
-       We apply a further scale factor of 8.
-       What's actually stored is 1/divisor so that the inner loop can
-       use a multiplication rather than a division. */
-    const AAN_SCALES: [f32; 8] = [
-        1.0, 1.387039845, 1.306562965, 1.175875602,
-        1.0, 0.785694958, 0.541196100, 0.275899379,
-    ];
+// The components of the frame and how they are sampled.
+#[derive(Clone)]
+struct Frame {
+    width: usize,
+    height: usize,
+    // Bits per sample: 8, or 12 for u16 input.
+    precision: u8,
+    // 1 for grayscale, 3 for YCbCr, 4 for CMYK or YCCK.
+    num_components: usize,
+    // Whether the four components are YCCK rather than CMYK.
+    ycck: bool,
+    // Entropy tables of each component: 1 for the second and third
+    // components of three-component and YCCK frames, 0 for the others.
+    tables: [usize; 4],
+    // Quantization table slot of each component.
+    quant_tables: [usize; 4],
+    // Entropy table slot of each component in scan headers. Sequential
+    // Huffman-coded frames can use tables given by the caller, and the
+    // others use `tables`.
+    huff_tables: [usize; 4],
+    // Sampling factors of the luma component. Chroma is always 1x1.
+    h_samp: usize,
+    v_samp: usize,
+    // Whether blocks are converted, transformed and quantized with integer
+    // arithmetic only.
+    fixed_point: bool,
+}
 
-    // Build (de)quantization tables
-    for y in 0..8 {
-        for x in 0..8 {
-            let i = y * 8 + x;
-            let luma = mem.qt_luma[ZIG_ZAG[i]] as f32;
-            let chroma = mem.qt_chroma[ZIG_ZAG[i]] as f32;
-            pqt_luma[i] = 1.0 / (8.0 * AAN_SCALES[x] * AAN_SCALES[y] * luma);
-            pqt_chroma[i] = 1.0 / (8.0 * AAN_SCALES[x] * AAN_SCALES[y] * chroma);
+impl Frame {
+    fn new<T: Sample>(image: &ImageView<T>, options: &EncoderOptions) -> Frame {
+        // Grayscale input produces a single-component frame, and CMYK input
+        // a four-component one. Lossless frames store the channels as they
+        // are.
+        let num_components = match image.format {
+            PixelFormat::Gray | PixelFormat::GrayAlpha => 1,
+            PixelFormat::Cmyk => 4,
+            _ => 3,
+        };
+        let ycck = num_components == 4 && options.ycck && options.lossless.is_none();
+        let tables = match num_components {
+            3 => [0, 1, 1, 0],
+            4 if ycck => [0, 1, 1, 0],
+            _ => [0; 4],
+        };
+        // By default, quantization tables follow the entropy tables, as far
+        // as there are tables.
+        let num_quant_tables = match options.quantization_tables.len() {
+            0 => 2,
+            n => n,
+        };
+        let quant_tables = match options.component_quantization {
+            Some(slots) => slots.map(|slot| slot as usize),
+            None => tables.map(|t| t.min(num_quant_tables - 1)),
+        };
+        let custom_huffman = !options.huffman_tables.is_empty()
+            && !options.optimize_huffman
+            && !options.progressive
+            && !options.arithmetic_coding
+            && options.lossless.is_none();
+        let huff_tables = if !custom_huffman {
+            tables
+        } else {
+            let num_huff_tables = options.huffman_tables.len();
+            match options.component_huffman {
+                Some(slots) => slots.map(|slot| slot as usize),
+                None => tables.map(|t| t.min(num_huff_tables - 1)),
+            }
+        };
+        // Only YCbCr frames are subsampled.
+        let (h_samp, v_samp) = if num_components == 3 && options.lossless.is_none() {
+            options.subsampling.luma_factors()
+        } else {
+            (1, 1)
+        };
+        Frame {
+            width: image.width as usize,
+            height: image.height as usize,
+            precision: T::PRECISION,
+            num_components,
+            ycck,
+            tables,
+            quant_tables,
+            huff_tables,
+            h_samp,
+            v_samp,
+            fixed_point: options.fixed_point,
         }
     }
 
-    let mut out: Vec<u8> = vec![];
+    // Number of entropy tables of each class.
+    fn num_tables(&self) -> usize {
+        if self.tables.contains(&1) {
+            2
+        } else {
+            1
+        }
+    }
 
-    // Write header
-    {
-        // SOI
-        out.write_u16::<BigEndian>(0xffd8).unwrap();
-        // APP0
-        out.write_u16::<BigEndian>(0xffe0).unwrap();
-        // JFIF length
-        out.write_u16::<BigEndian>(20 - 4).unwrap();
-        // JFIF ID
-        out.extend_from_slice(b"JFIF\0");
-        // Version
-        out.write_u16::<BigEndian>(0x0102).unwrap();
-        // Dots-per-inch
-        out.push(0x01);
-        // X Density - 96 DPI
-        out.write_u16::<BigEndian>(0x0060).unwrap();
-        // Y Density - 96 DPI
-        out.write_u16::<BigEndian>(0x0060).unwrap();
-        // X thumb, Y thumb
-        out.push(0);
-        out.push(0);
+    // Component index of each block in an MCU, in coding order: the luma
+    // blocks left to right and top to bottom, then one block per chroma
+    // component.
+    fn mcu_components(&self) -> Vec<usize> {
+        let mut components = vec![0; self.h_samp * self.v_samp];
+        components.extend(1..self.num_components);
+        components
     }
 
-    // Write comment
-    {
-        let c = b"Created by Tiny JPEG Encoder";
-        // Comment
-        out.write_u16::<BigEndian>(0xfffe).unwrap();
-        // Comment length
-        let len = c.len() as u16 + 2;
-        out.write_u16::<BigEndian>(len).unwrap();
-        // Comment string
-        out.extend_from_slice(c);
+    fn sampling(&self, c: usize) -> (usize, usize) {
+        if c == 0 {
+            (self.h_samp, self.v_samp)
+        } else {
+            (1, 1)
+        }
     }
 
-    // Write quantization tables
-    append_dqt(&mut out, &mem.qt_luma, 0);
-    append_dqt(&mut out, &mem.qt_chroma, 1);
+    fn mcus_per_row(&self) -> usize {
+        self.width.div_ceil(8 * self.h_samp)
+    }
 
-    // Write the frame marker
-    {
-        // SOF
-        out.write_u16::<BigEndian>(0xffc0).unwrap();
-        // Len
-        out.write_u16::<BigEndian>(8 + 3 * 3).unwrap();
-        // Precision
-        out.push(8);
-        // Height
-        debug_assert!(h <= 0xffff);
-        out.write_u16::<BigEndian>(h as u16).unwrap();
-        // Width
-        debug_assert!(w <= 0xffff);
-        out.write_u16::<BigEndian>(w as u16).unwrap();
-        // Number of components
-        out.push(3);
-        // Component spec
-        let tables = [0, 1, 1];
-        for i in 0..3 {
-            out.push(i + 1); // No particular reason. Just 1, 2, 3.
-            out.push(0x11);
-            out.push(tables[i as usize]);
+    fn mcu_rows(&self) -> usize {
+        self.height.div_ceil(8 * self.v_samp)
+    }
+
+    // Number of blocks per row and per column of component `c` that a scan of
+    // that component alone covers. MCUs may have more blocks past the edges.
+    fn component_blocks(&self, c: usize) -> (usize, usize) {
+        let (h, v) = self.sampling(c);
+        let width = (self.width * h).div_ceil(self.h_samp);
+        let height = (self.height * v).div_ceil(self.v_samp);
+        (width.div_ceil(8), height.div_ceil(8))
+    }
+
+    // Index of block (bx, by) of component `c` in coefficients stored MCU by
+    // MCU, in the order of `mcu_components`.
+    fn block_index(&self, c: usize, bx: usize, by: usize) -> usize {
+        let (h, v) = self.sampling(c);
+        let mcu = (by / v) * self.mcus_per_row() + bx / h;
+        let offset = if c == 0 {
+            (by % v) * h + bx % h
+        } else {
+            self.h_samp * self.v_samp + c - 1
+        };
+        mcu * (self.h_samp * self.v_samp + self.num_components - 1) + offset
+    }
+}
+
+/* How blocks are quantized with the tables of a `State`, in frames of any
+   size and layout. */
+#[derive(Clone)]
+struct Quantizer {
+    // The scaled tables of `State`.
+    pqt: Vec<[f32; 64]>,
+    divisors: Vec<[i32; 64]>,
+    trellis: Option<trellis::Trellis>,
+    // Transform and quantization kernels for this CPU.
+    kernels: simd::Kernels,
+}
+
+impl Quantizer {
+    fn new(mem: &State, options: &EncoderOptions) -> Quantizer {
+        let trellis = options.trellis.map(|lambda| trellis::Trellis::new(lambda, mem));
+        Quantizer {
+            pqt: mem.pqt.clone(),
+            divisors: mem.divisors.clone(),
+            trellis,
+            kernels: simd::Kernels::detect(),
+        }
+    }
+
+    /* Quantizes a transformed block of component `c`. With trellis
+       quantization, the DC coefficient is only rounded, and its value before
+       rounding is returned so that the DC coefficients of a row can be
+       chosen together. */
+    fn quantize(&self, du: &mut [i16; 64], dct_mcu: &[f32; 64], frame: &Frame, c: usize) -> f32 {
+        let qt = &self.pqt[frame.quant_tables[c]];
+        match self.trellis {
+            Some(ref trellis) => {
+                let mut coefs = [0f32; 64];
+                divide_du(&mut coefs, dct_mcu, qt);
+                trellis.quantize_ac(du, &coefs, frame.huff_tables[c]);
+                du[0] = (coefs[0] + 0.5).floor() as i16;
+                coefs[0]
+            }
+            None if frame.fixed_point => {
+                (self.kernels.quantize_fixed)(du, dct_mcu, &self.divisors[frame.quant_tables[c]]);
+                0.0
+            }
+            None => {
+                (self.kernels.quantize)(du, dct_mcu, qt);
+                0.0
+            }
         }
     }
+}
 
-    // TODO: Use enums TJEI_LUMA/CHROMA_DC/AC and TJEI_DC/AC
-    append_dht(&mut out, &mem.ht_bits[0], &mem.ht_vals[0], 0, 0);
-    append_dht(&mut out, &mem.ht_bits[1], &mem.ht_vals[1], 1, 0);
-    append_dht(&mut out, &mem.ht_bits[2], &mem.ht_vals[2], 0, 1);
-    append_dht(&mut out, &mem.ht_bits[3], &mem.ht_vals[3], 1, 1);
+// Where the transformed blocks of a frame come from: an image, or the blocks
+// of an image that was transformed before.
+trait BlockSource: Sync {
+    /* Passes the DCT coefficients of the blocks of each MCU in `rows`, a range
+       of rows of MCUs, to `f`, left to right and top to bottom, ordered as in
+       `Frame::mcu_components`. Images are transformed with `kernels`. */
+    fn for_each_transformed_mcu(
+        &self,
+        frame: &Frame,
+        kernels: simd::Kernels,
+        rows: Range<usize>,
+        f: &mut dyn FnMut(&[[f32; 64]]),
+    );
+}
 
-    // Write start of scan
-    {
-        // SOS
-        out.write_u16::<BigEndian>(0xffda).unwrap();
-        // Length = 6 + (frame component spec * 3) = 12
-        out.write_u16::<BigEndian>(12).unwrap();
-        // Number of components
-        out.push(3);
+/* The arithmetic that images are converted and transformed with: floating
+   point, or integers only, as in `fixed_point`. MCUs are gathered into planes
+   of `Value`s, and each block is then transformed into `f32` coefficients. */
+trait Pipeline {
+    type Value: Copy + Default;
+
+    // The value of a sample of a component that isn't converted to YCbCr,
+    // or, with `inverted`, of the largest sample minus it.
+    fn sample(&self, sample: u16, inverted: bool) -> Self::Value;
+    // The value of a sample that is converted to YCbCr with the others of
+    // its pixel.
+    fn color(&self, sample: u16) -> Self::Value;
+    // Converts the first `len` pixels of the first three planes from R, G
+    // and B to Y, Cb and Cr.
+    fn rgb_to_ycbcr(&self, planes: &mut [[Self::Value; 256]; 4], len: usize);
+    // Averages `h_samp` x `v_samp` pixels of a plane into each value of an
+    // 8x8 block.
+    fn downsample(
+        &self,
+        du: &mut [Self::Value; 64],
+        plane: &[Self::Value; 256],
+        h_samp: usize,
+        v_samp: usize,
+    );
+    // Transforms an 8x8 block of values into `block`.
+    fn transform(&self, block: &mut [f32; 64], du: &mut [Self::Value; 64]);
+}
+
+// The float pipeline. Samples are centered on zero as they are gathered, or,
+// for YCbCr, as they are converted.
+struct FloatPipeline {
+    kernels: simd::Kernels,
+    level_shift: f32,
+    max: f32,
+}
 
-        let tables = [0x00, 0x11, 0x11];
-        for i in 0..3 {
-            /* Component ID -  Must be equal to component_id from frame header
-               above. */
-            out.push(i + 1);
-            // dc_ac
-            out.push(tables[i as usize]);
+impl Pipeline for FloatPipeline {
+    type Value = f32;
 
+    fn sample(&self, sample: u16, inverted: bool) -> f32 {
+        let sample = sample as f32;
+        if inverted {
+            self.max - sample - self.level_shift
+        } else {
+            sample - self.level_shift
         }
-        // First
-        out.push(0);
-        // Last
-        out.push(63);
-        // ah_al
-        out.push(0);
     }
 
-    /* Write compressed data
-       --------------------- */
+    fn color(&self, sample: u16) -> f32 {
+        sample as f32
+    }
 
-    let mut du_y = [0f32; 64];
-    let mut du_b = [0f32; 64];
-    let mut du_r = [0f32; 64];
-    // Set diff to 0
-    let mut pred_y = 0;
-    let mut pred_b = 0;
-    let mut pred_r = 0;
-    // Bit stack
-    let mut bitbuffer = 0u32;
-    let mut location = 0u32;
+    fn rgb_to_ycbcr(&self, planes: &mut [[f32; 256]; 4], len: usize) {
+        (self.kernels.rgb_to_ycbcr)(planes, len, self.level_shift);
+    }
+
+    fn downsample(&self, du: &mut [f32; 64], plane: &[f32; 256], h_samp: usize, v_samp: usize) {
+        downsample(du, plane, h_samp, v_samp);
+    }
+
+    fn transform(&self, block: &mut [f32; 64], du: &mut [f32; 64]) {
+        *block = *du;
+        (self.kernels.fdct)(block);
+    }
+}
 
-    let mut y = 0;
+impl<'a, T: Sample> BlockSource for ImageView<'a, T> {
+    fn for_each_transformed_mcu(
+        &self,
+        frame: &Frame,
+        kernels: simd::Kernels,
+        rows: Range<usize>,
+        f: &mut dyn FnMut(&[[f32; 64]]),
+    ) {
+        if frame.fixed_point {
+            let pipeline = fixed_point::FixedPoint::new(kernels, frame.precision);
+            transform_mcus(self, frame, rows, &pipeline, f);
+        } else {
+            let pipeline = FloatPipeline {
+                kernels,
+                // Samples are centered on zero before the DCT.
+                level_shift: (1 << (frame.precision - 1)) as f32,
+                max: ((1 << frame.precision) - 1) as f32,
+            };
+            transform_mcus(self, frame, rows, &pipeline, f);
+        }
+    }
+}
 
-    while y < h {
+// Converts, downsamples and transforms the image one MCU at a time.
+fn transform_mcus<T: Sample, P: Pipeline>(
+    image: &ImageView<T>,
+    frame: &Frame,
+    rows: Range<usize>,
+    pipeline: &P,
+    f: &mut dyn FnMut(&[[f32; 64]]),
+) {
+    let data = image.data;
+    let offsets = image.format.channel_offsets();
+    let (w, h) = (frame.width, frame.height);
+    let (h_samp, v_samp) = (frame.h_samp, frame.v_samp);
+    let mcu_w = 8 * h_samp;
+    let mcu_h = 8 * v_samp;
+
+    // Full-resolution samples of each component of one MCU, row-major,
+    // mcu_w * mcu_h.
+    let mut mcu_planes = [[P::Value::default(); 256]; 4];
+    let mut du = [P::Value::default(); 64];
+    let mut blocks = vec![[0f32; 64]; frame.mcu_components().len()];
+
+    let mut y = rows.start * mcu_h;
+
+    while y < h.min(rows.end * mcu_h) {
         let mut x = 0;
         while x < w {
 
             // Block loop: ====
-            for off_y in 0..8 {
-                for off_x in 0..8 {
-                    let block_idx = off_y * 8 + off_x;
-                    let mut src_idx = (((y + off_y) * w) + (x + off_x)) * num_components;
-                    let col = x + off_x;
-                    let row = y + off_y;
-
-                    if row >= h {
-                        src_idx -= (w * (row - h + 1)) * num_components;
+            for off_y in 0..mcu_h {
+                for off_x in 0..mcu_w {
+                    let mcu_idx = off_y * mcu_w + off_x;
+                    // Pixels past the right and bottom edges repeat the last
+                    // column and row.
+                    let col = (x + off_x).min(w - 1);
+                    let row = (y + off_y).min(h - 1);
+                    let src_idx = image.offset(col, row);
+                    let sample = |c: usize| sample_value(data[src_idx + offsets[c]]);
+
+                    if frame.num_components == 1 {
+                        mcu_planes[0][mcu_idx] = pipeline.sample(sample(0), false);
+                        continue;
                     }
 
-                    if col >= w {
-                        src_idx -= (col - w + 1) * num_components;
+                    /* CMYK is stored inverted, as Adobe does, so that 0 is
+                       full ink. YCCK converts C, M and Y to YCbCr as if they
+                       were R, G and B, which decoders turn back into the
+                       inverted values. K is stored inverted either way. */
+                    if frame.num_components == 4 {
+                        mcu_planes[3][mcu_idx] = pipeline.sample(sample(3), true);
+                        if !frame.ycck {
+                            for (c, plane) in mcu_planes[..3].iter_mut().enumerate() {
+                                plane[mcu_idx] = pipeline.sample(sample(c), true);
+                            }
+                            continue;
+                        }
                     }
-                    debug_assert!(src_idx < w * h * num_components);
-
-                    let r = data[src_idx as usize + 0] as f32;
-                    let g = data[src_idx as usize + 1] as f32;
-                    let b = data[src_idx as usize + 2] as f32;
-
-                    let luma: f32 = 0.299 * r + 0.587 * g + 0.114 * b - 128.0;
-                    let cb: f32 = -0.1687 * r - 0.3313 * g + 0.5 * b;
-                    let cr: f32 = 0.5 * r - 0.4187 * g - 0.0813 * b;
 
-                    let block_idx = block_idx as usize;
-                    du_y[block_idx] = luma;
-                    du_b[block_idx] = cb;
-                    du_r[block_idx] = cr;
+                    for (c, plane) in mcu_planes[..3].iter_mut().enumerate() {
+                        plane[mcu_idx] = pipeline.color(sample(c));
+                    }
                 }
             }
+            // The R, G and B samples are converted together.
+            if frame.num_components == 3 || frame.ycck {
+                pipeline.rgb_to_ycbcr(&mut mcu_planes, mcu_w * mcu_h);
+            }
             // ===============
 
-            encode_and_append_mcu(
-                &mut out,
-                &du_y,
-                &pqt_luma,
-                &mem.ehuffsize[0],
-                &mem.ehuffcode[0],
-                &mem.ehuffsize[1],
-                &mem.ehuffcode[1],
-                &mut pred_y,
-                &mut bitbuffer,
-                &mut location,
-            );
-            encode_and_append_mcu(
-                &mut out,
-                &du_b,
-                &pqt_chroma,
-                &mem.ehuffsize[2],
-                &mem.ehuffcode[2],
-                &mem.ehuffsize[3],
-                &mem.ehuffcode[3],
-                &mut pred_b,
-                &mut bitbuffer,
-                &mut location,
-            );
-            encode_and_append_mcu(
-                &mut out,
-                &du_r,
-                &pqt_chroma,
-                &mem.ehuffsize[2],
-                &mem.ehuffcode[2],
-                &mem.ehuffsize[3],
-                &mem.ehuffcode[3],
-                &mut pred_r,
-                &mut bitbuffer,
-                &mut location,
-            );
+            // Luma blocks, left to right and top to bottom.
+            let mut k = 0;
+            for block_y in 0..v_samp {
+                for block_x in 0..h_samp {
+                    for off_y in 0..8 {
+                        for off_x in 0..8 {
+                            let mcu_idx = (block_y * 8 + off_y) * mcu_w + block_x * 8 + off_x;
+                            du[off_y * 8 + off_x] = mcu_planes[0][mcu_idx];
+                        }
+                    }
+                    pipeline.transform(&mut blocks[k], &mut du);
+                    k += 1;
+                }
+            }
 
-            x += 8;
-        }
-        y += 8;
-    }
+            // One block for each other component, averaging h_samp x v_samp
+            // pixels into each sample.
+            for plane in mcu_planes[1..frame.num_components].iter() {
+                pipeline.downsample(&mut du, plane, h_samp, v_samp);
+                pipeline.transform(&mut blocks[k], &mut du);
+                k += 1;
+            }
 
-    /* Finish the image
-       ---------------- */
-    if location > 0 && location < 8 {
-        let num_bits = (8 - location) as u16;
-        append_bits(&mut out, &mut bitbuffer, &mut location, num_bits, 0);
-    }
-    // EOI
-    out.write_u16::<BigEndian>(0xffd9).unwrap();
+            f(&blocks);
 
-    out
+            x += mcu_w;
+        }
+        y += mcu_h;
+    }
 }
 
-pub enum Quality {
-    Medium,
-    High,
-    Highest,
+impl BlockSource for Vec<[f32; 64]> {
+    fn for_each_transformed_mcu(
+        &self,
+        frame: &Frame,
+        _kernels: simd::Kernels,
+        rows: Range<usize>,
+        f: &mut dyn FnMut(&[[f32; 64]]),
+    ) {
+        let mcus_per_row = frame.mcus_per_row();
+        let mcus = self.chunks(frame.mcu_components().len());
+        for mcu in mcus.skip(rows.start * mcus_per_row).take(rows.len() * mcus_per_row) {
+            f(mcu);
+        }
+    }
 }
 
-/// Takes bitmap data and writes a JPEG-encoded image to disk at the highest
-/// quality.
-pub fn encode_to_file(
-    dest: &Path,
-    w: i32,
-    h: i32,
-    num_components: i32,
-    data: &[u8],
-) -> Result<(), io::Error> {
-    encode_to_file_at_quality(dest, Quality::Highest, w, h, num_components, data)
+/* Quantizes transformed MCUs in order, left to right and top to bottom,
+   keeping what trellis quantization carries from one row to the next. */
+struct McuQuantizer {
+    mcu_components: Vec<usize>,
+    blocks: Vec<[i16; 64]>,
+    dcs: Vec<f32>,
+    // The blocks of the current row of MCUs and their unrounded DC
+    // coefficients, for the DC trellis.
+    row_blocks: Vec<[i16; 64]>,
+    row_dcs: Vec<f32>,
+    dc_pred: [i32; 4],
+    mcu_index: usize,
+    restart_interval: usize,
 }
 
-/// Takes bitmap data and writes a JPEG-encoded image to disk at the specified
-/// quality.
-pub fn encode_to_file_at_quality(
-    dest: &Path,
-    quality: Quality,
-    w: i32,
-    h: i32,
-    num_components: i32,
-    data: &[u8],
-) -> Result<(), io::Error> {
-    let mut f = File::create(dest)?;
-    let encoded_bytes = encode_to_buffer(quality, w, h, num_components, data);
-    f.write_all(&encoded_bytes)?;
-    Ok(())
-}
+impl McuQuantizer {
+    // Starts at MCU `mcu_index`, the first of a row.
+    fn new(frame: &Frame, restart_interval: usize, mcu_index: usize) -> McuQuantizer {
+        let mcu_components = frame.mcu_components();
+        let n = mcu_components.len();
+        McuQuantizer {
+            mcu_components,
+            blocks: vec![[0i16; 64]; n],
+            dcs: vec![0f32; n],
+            row_blocks: vec![],
+            row_dcs: vec![],
+            dc_pred: [0; 4],
+            mcu_index,
+            restart_interval,
+        }
+    }
 
-/// Returns a JPEG-encoded buffer, given bitmap data
-pub fn encode_to_buffer(
-    quality: Quality,
-    w: i32,
-    h: i32,
-    num_components: i32,
-    data: &[u8],
-) -> Vec<u8> {
+    // Whether the DC predictions restart before MCU `mcu_index`.
+    fn resets(&self, mcu_index: usize) -> bool {
+        mcu_index == 0 || starts_restart_interval(mcu_index, self.restart_interval)
+    }
 
-    let qt_factor: u8 = match quality {
-        Quality::High => 10,
-        _ => 1,
-    };
+    /* Quantizes the blocks of the next MCU. `f` gets the quantized blocks of
+       each MCU, ordered as in `Frame::mcu_components`. With trellis
+       quantization, the MCUs of a row are passed on once the whole row is
+       quantized. */
+    fn push<F>(&mut self, frame: &Frame, quant: &Quantizer, mcu: &[[f32; 64]], f: &mut F)
+    where
+        F: FnMut(&[[i16; 64]]),
+    {
+        let mcu_components = &self.mcu_components;
+        let n = mcu_components.len();
+        let mcus_per_row = frame.mcus_per_row();
+        for (k, (dct_mcu, &c)) in mcu.iter().zip(mcu_components.iter()).enumerate() {
+            self.dcs[k] = quant.quantize(&mut self.blocks[k], dct_mcu, frame, c);
+        }
+        self.mcu_index += 1;
 
-    let mut mem = State {
-        ehuffsize: [[0u8; 257]; 4],
-        ehuffcode: [[0u16; 256]; 4],
-        ht_bits: [
-            &DEFAULT_HT_LUMA_DC_LEN,
-            &DEFAULT_HT_LUMA_AC_LEN,
-            &DEFAULT_HT_CHROMA_DC_LEN,
-            &DEFAULT_HT_CHROMA_AC_LEN,
-        ],
-        ht_vals: [
-            &DEFAULT_HT_LUMA_DC,
-            &DEFAULT_HT_LUMA_AC,
-            &DEFAULT_HT_CHROMA_DC,
-            &DEFAULT_HT_CHROMA_AC,
-        ],
-        qt_luma: [1; QT_SIZE],
-        qt_chroma: [1; QT_SIZE],
-    };
+        let trellis = match quant.trellis {
+            Some(ref trellis) => trellis,
+            None => {
+                f(&self.blocks);
+                return;
+            }
+        };
+        self.row_blocks.extend_from_slice(&self.blocks);
+        self.row_dcs.extend_from_slice(&self.dcs);
+        if self.mcu_index % mcus_per_row != 0 {
+            return;
+        }
 
-    match quality {
-        Quality::Highest => {}
-        Quality::High | Quality::Medium => {
-            for i in 0..QT_SIZE {
-                if DEFAULT_QT_LUMA_FROM_SPEC[i] != 0 {
-                    mem.qt_luma[i] = DEFAULT_QT_LUMA_FROM_SPEC[i] / qt_factor;
-                }
-                if DETAULT_QT_CHROMA_FROM_PAPER[i] != 0 {
-                    mem.qt_chroma[i] = DETAULT_QT_CHROMA_FROM_PAPER[i] / qt_factor;
-                }
+        let first_mcu = self.mcu_index - mcus_per_row;
+        for c in 0..frame.num_components {
+            // The blocks of the component in coding order. Predictions start
+            // over at the first of them in a restart interval.
+            let first = mcu_components.iter().position(|&b| b == c).unwrap();
+            let positions: Vec<usize> =
+                (0..self.row_blocks.len()).filter(|&i| mcu_components[i % n] == c).collect();
+            let values: Vec<f32> = positions.iter().map(|&i| self.row_dcs[i]).collect();
+            let resets: Vec<bool> = positions
+                .iter()
+                .map(|&i| i % n == first && self.resets(first_mcu + i / n))
+                .collect();
+            let slot = frame.huff_tables[c];
+            let chosen = trellis.quantize_dc(&values, &resets, &mut self.dc_pred[c], slot);
+            for (&i, &dc) in positions.iter().zip(chosen.iter()) {
+                self.row_blocks[i][0] = dc;
             }
         }
+        for mcu in self.row_blocks.chunks(n) {
+            f(mcu);
+        }
+        self.row_blocks.clear();
+        self.row_dcs.clear();
     }
+}
 
-    huff_expand(&mut mem);
-    encode_main(&mem, w, h, num_components, data)
+// Quantizes the blocks of `source` in `rows`, a range of rows of MCUs, with a
+// `McuQuantizer`.
+fn for_each_mcu<F>(
+    frame: &Frame,
+    source: &dyn BlockSource,
+    rows: Range<usize>,
+    quant: &Quantizer,
+    restart_interval: usize,
+    mut f: F,
+) where
+    F: FnMut(&[[i16; 64]]),
+{
+    let mut mcus = McuQuantizer::new(frame, restart_interval, rows.start * frame.mcus_per_row());
+    source.for_each_transformed_mcu(frame, quant.kernels, rows, &mut |mcu| {
+        mcus.push(frame, quant, mcu, &mut f)
+    });
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+// Number of threads that `options` code sequential scans on.
+fn thread_count(options: &EncoderOptions) -> usize {
+    match options.threads {
+        Some(0) => thread::available_parallelism().map_or(1, |n| n.get()),
+        Some(n) => n,
+        None => 1,
+    }
+}
 
-    #[test]
-    fn white_texture() {
-        let dest = Path::new("./out.jpg");
-        const W: i32 = 4000;
-        const H: i32 = 2000;
-        const C: i32 = 4;
-        let data = vec![255u8; (W * H * C) as usize];
-        assert!(encode_to_file(dest, W, H, C, &data).is_ok());
+/* Splits the rows of MCUs of `frame` into `threads` bands of whole restart
+   intervals, which are then whole rows, and runs `f` on the rows of each band
+   on a thread of its own. Returns the results in the order of the bands. */
+fn map_bands<R, F>(frame: &Frame, restart_interval: usize, threads: usize, f: F) -> Vec<R>
+where
+    R: Send,
+    F: Fn(Range<usize>) -> R + Sync,
+{
+    let rows = frame.mcu_rows();
+    if threads <= 1 || rows <= 1 {
+        return vec![f(0..rows)];
+    }
+    let interval_rows = restart_interval / frame.mcus_per_row();
+    let band_rows = rows.div_ceil(interval_rows).div_ceil(threads) * interval_rows;
+    let f = &f;
+    thread::scope(|scope| {
+        let bands: Vec<_> = (0..rows)
+            .step_by(band_rows)
+            .map(|start| scope.spawn(move || f(start..rows.min(start + band_rows))))
+            .collect();
+        bands.into_iter().map(|band| band.join().unwrap()).collect()
+    })
+}
+
+// Codes the quantized MCUs of a sequential Huffman-coded scan in order.
+struct ScanCoder {
+    mcu_components: Vec<usize>,
+    // Set diff to 0
+    pred: [i32; 4],
+    // Bit stack
+    bitbuffer: u32,
+    location: u32,
+    mcu_index: usize,
+}
+
+impl ScanCoder {
+    // Starts at MCU `mcu_index`.
+    fn new(frame: &Frame, mcu_index: usize) -> ScanCoder {
+        ScanCoder {
+            mcu_components: frame.mcu_components(),
+            pred: [0; 4],
+            bitbuffer: 0,
+            location: 0,
+            mcu_index,
+        }
+    }
+
+    // Codes the next MCU with the tables of `mem`, after a restart marker if
+    // it starts a restart interval.
+    fn encode_mcu(
+        &mut self,
+        out: &mut Writer,
+        mem: &State,
+        frame: &Frame,
+        restart_interval: usize,
+        mcu: &[[i16; 64]],
+    ) {
+        if starts_restart_interval(self.mcu_index, restart_interval) {
+            let n = self.mcu_index / restart_interval - 1;
+            append_restart(out, &mut self.bitbuffer, &mut self.location, n);
+            self.pred = [0; 4];
+        }
+        self.mcu_index += 1;
+        for (du, &c) in mcu.iter().zip(self.mcu_components.iter()) {
+            let t = 2 * frame.huff_tables[c];
+            encode_and_append_mcu(
+                out,
+                du,
+                &mem.ehuffsize[t],
+                &mem.ehuffcode[t],
+                &mem.ehuffsize[t + 1],
+                &mem.ehuffcode[t + 1],
+                &mut self.pred[c],
+                &mut self.bitbuffer,
+                &mut self.location,
+            );
+        }
+    }
+
+    // Writes out the last bits.
+    fn finish(&mut self, out: &mut Writer) {
+        flush_bits(out, &mut self.bitbuffer, &mut self.location);
+    }
+}
+
+// Writes the Huffman tables of `mem` that the components use and the start of
+// the single scan of a sequential frame.
+fn append_sequential_header(out: &mut Writer, mem: &State, frame: &Frame) {
+    // The DC and AC tables of every slot that components use
+    for slot in 0..mem.ht_bits.len() / 2 {
+        if frame.huff_tables[..frame.num_components].contains(&slot) {
+            let (dc, ac) = (2 * slot, 2 * slot + 1);
+            append_dht(out, &mem.ht_bits[dc], &mem.ht_vals[dc], 0, slot as u8);
+            append_dht(out, &mem.ht_bits[ac], &mem.ht_vals[ac], 1, slot as u8);
+        }
+    }
+
+    // Write start of scan
+    let scan_components: Vec<usize> = (0..frame.num_components).collect();
+    append_sos(out, frame, &scan_components, 0, 63, 0);
+}
+
+/* Writes the Huffman tables and the single scan of a sequential frame. With
+   more than one thread, the rows of MCUs are split into bands, which are
+   quantized and coded in parallel. Each band starts a restart interval and
+   is coded as it would be in one piece, so the scan is the same with any
+   number of threads. */
+fn append_sequential_scan(
+    out: &mut Writer,
+    mem: &State,
+    options: &EncoderOptions,
+    frame: &Frame,
+    source: &dyn BlockSource,
+    quant: &Quantizer,
+    restart_interval: usize,
+) {
+    let threads = thread_count(options);
+    // Component index of each block in an MCU, and the Huffman tables of each
+    // component.
+    let mcu_components = frame.mcu_components();
+    let huff_tables = frame.huff_tables.map(|t| 2 * t);
+    let mcus_per_row = frame.mcus_per_row();
+
+    /* With optimized Huffman tables, the whole image is quantized first, and
+       the symbol counts give the tables. Otherwise every MCU is written as
+       soon as it is quantized. The example tables have no codes for the
+       larger coefficients of 12-bit frames, which are always optimized
+       unless the caller gave tables. */
+    let custom = !options.huffman_tables.is_empty();
+    let optimize = options.optimize_huffman || (frame.precision > 8 && !custom);
+    let mut coefficients = vec![];
+    let optimized;
+    let mem = if optimize {
+        let bands = map_bands(frame, restart_interval, threads, |rows| {
+            let mut coefficients = vec![];
+            for_each_mcu(frame, source, rows, quant, restart_interval, |mcu| {
+                coefficients.extend_from_slice(mcu)
+            });
+            coefficients
+        });
+        for band in bands {
+            coefficients.extend(band);
+        }
+
+        let mut freq = [[0u32; 257]; 4];
+        let mut pred = [0i32; 4];
+        for (i, mcu) in coefficients.chunks(mcu_components.len()).enumerate() {
+            if starts_restart_interval(i, restart_interval) {
+                pred = [0; 4];
+            }
+            for (du, &c) in mcu.iter().zip(mcu_components.iter()) {
+                let t = huff_tables[c];
+                let (dc, ac) = freq[t..t + 2].split_at_mut(1);
+                gather_du_stats(du, &mut pred[c], &mut dc[0], &mut ac[0]);
+            }
+        }
+
+        let mut state = mem.clone();
+        state.ht_bits = vec![[0; 16]; 4];
+        state.ht_vals = vec![vec![]; 4];
+        for (i, freq) in freq.iter().enumerate() {
+            if freq.iter().any(|&f| f != 0) {
+                let (bits, vals) = huff_optimal_table(freq);
+                state.ht_bits[i] = bits;
+                state.ht_vals[i] = vals;
+            }
+        }
+        huff_expand(&mut state);
+        optimized = state;
+        &optimized
+    } else {
+        mem
+    };
+
+    append_sequential_header(out, mem, frame);
+
+    /* Write compressed data
+       --------------------- */
+
+    let code_rows = |out: &mut Writer, rows: Range<usize>| {
+        let mut coder = ScanCoder::new(frame, rows.start * mcus_per_row);
+        let mut encode_mcu = |mcu: &[[i16; 64]]| {
+            coder.encode_mcu(out, mem, frame, restart_interval, mcu)
+        };
+        if optimize {
+            let mcus = coefficients.chunks(mcu_components.len());
+            for mcu in mcus.skip(rows.start * mcus_per_row).take(rows.len() * mcus_per_row) {
+                encode_mcu(mcu);
+            }
+        } else {
+            for_each_mcu(frame, source, rows, quant, restart_interval, encode_mcu);
+        }
+        coder.finish(out);
+    };
+    // Bands coded on other threads are held in memory until they are
+    // written out in order.
+    if threads <= 1 || frame.mcu_rows() <= 1 {
+        code_rows(out, 0..frame.mcu_rows());
+        return;
+    }
+    let bands = map_bands(frame, restart_interval, threads, |rows| {
+        let mut band = Writer::buffer();
+        code_rows(&mut band, rows);
+        band.into_bytes()
+    });
+    for band in bands {
+        out.extend_from_slice(&band);
+    }
+}
+
+// Writes everything before the first scan: the markers, the quantization
+// tables, the frame header and the restart interval.
+fn append_header(
+    out: &mut Writer,
+    mem: &State,
+    options: &EncoderOptions,
+    frame: &Frame,
+    restart_interval: usize,
+) {
+    // SOI
+    out.write_u16(0xffd8);
+
+    /* JFIF only allows grayscale and YCbCr. An Adobe segment tells
+       decoders that the components of lossless RGB frames are RGB, and
+       those of four-component frames CMYK or YCCK. */
+    if frame.num_components == 4 || (options.lossless.is_some() && frame.num_components == 3) {
+        append_adobe(out, if frame.ycck { 2 } else { 0 });
+    } else {
+        // APP0
+        out.write_u16(0xffe0);
+        // JFIF length
+        out.write_u16(20 - 4);
+        // JFIF ID
+        out.extend_from_slice(b"JFIF\0");
+        // Version
+        out.write_u16(0x0102);
+        // Units, then X and Y density
+        let (units, x, y) = match options.metadata.density {
+            Density::AspectRatio(x, y) => (0, x, y),
+            Density::PerInch(x, y) => (1, x, y),
+            Density::PerCentimeter(x, y) => (2, x, y),
+        };
+        out.push(units);
+        out.write_u16(x);
+        out.write_u16(y);
+        // X thumb, Y thumb
+        out.push(0);
+        out.push(0);
+    }
+
+    // Write comment
+    if let Some(ref c) = options.metadata.comment {
+        // Comment
+        out.write_u16(0xfffe);
+        // Comment length
+        let len = c.len() as u16 + 2;
+        out.write_u16(len);
+        // Comment string
+        out.extend_from_slice(c);
+    }
+
+    // Write the quantization tables that components use, none for lossless
+    // frames
+    let used_qt = &frame.quant_tables[..frame.num_components];
+    if options.lossless.is_none() {
+        for (id, qt) in mem.qt.iter().enumerate() {
+            if used_qt.contains(&id) {
+                append_dqt(out, qt, id as u8, frame.precision);
+            }
+        }
+    }
+    let wide_qt = used_qt.iter().any(|&id| mem.qt[id].iter().any(|&q| q > 0xff));
+    // Baseline frames have two Huffman tables of each class at most.
+    let many_huff_tables = frame.huff_tables[..frame.num_components].iter().any(|&t| t > 1);
+
+    // Write the frame marker
+    {
+        /* SOF: baseline, progressive, or either with arithmetic coding.
+           12-bit frames, 16-bit quantization tables and more than two
+           Huffman tables can't be baseline, and use extended sequential
+           instead. Lossless frames are always Huffman-coded. */
+        let extended = frame.precision > 8 || wide_qt || many_huff_tables;
+        let marker = match (options.progressive, options.arithmetic_coding) {
+            _ if options.lossless.is_some() => 0xffc3,
+            (false, false) if extended => 0xffc1,
+            (false, false) => 0xffc0,
+            (true, false) => 0xffc2,
+            (false, true) => 0xffc9,
+            (true, true) => 0xffca,
+        };
+        out.write_u16(marker);
+        // Len
+        let len = 8 + 3 * frame.num_components as u16;
+        out.write_u16(len);
+        // Precision
+        out.push(frame.precision);
+        // Height
+        debug_assert!(frame.height <= 0xffff);
+        out.write_u16(frame.height as u16);
+        // Width
+        debug_assert!(frame.width <= 0xffff);
+        out.write_u16(frame.width as u16);
+        // Number of components
+        out.push(frame.num_components as u8);
+        // Component spec
+        let sampling = [((frame.h_samp as u8) << 4) | frame.v_samp as u8, 0x11, 0x11, 0x11];
+        for (i, &sampling) in sampling.iter().enumerate().take(frame.num_components) {
+            out.push(i as u8 + 1); // No particular reason. Just 1, 2, 3.
+            out.push(sampling);
+            // Quantization table, none for lossless frames
+            out.push(if options.lossless.is_some() { 0 } else { frame.quant_tables[i] as u8 });
+        }
+    }
+
+    // Write the restart interval
+    if restart_interval > 0 {
+        // DRI
+        out.write_u16(0xffdd);
+        // Len
+        out.write_u16(4);
+        out.write_u16(restart_interval as u16);
+    }
+}
+
+// The restart interval that `options` codes `frame` with, in MCUs.
+fn effective_restart_interval(options: &EncoderOptions, frame: &Frame) -> usize {
+    // Lossless restart intervals are whole rows of pixels.
+    let restart_interval = options.restart_interval as usize;
+    if options.lossless.is_some() {
+        return lossless::restart_interval(frame, restart_interval);
+    }
+    /* Sequential Huffman-coded scans of several rows of MCUs can be coded in
+       bands of rows on several threads. The bands start restart intervals,
+       which are then rounded up to whole rows, or one row if there are none. */
+    let bands = thread_count(options) > 1 && frame.mcu_rows() > 1;
+    if !options.progressive && !options.arithmetic_coding && bands {
+        let row = frame.mcus_per_row();
+        return restart_interval.div_ceil(row).clamp(1, 0xffff / row) * row;
+    }
+    restart_interval
+}
+
+// Encodes `image` with the tables of `mem`, quantized by `quant`, which is
+// built from them.
+fn encode_main<T: Sample>(
+    out: &mut Writer,
+    mem: &State,
+    quant: &Quantizer,
+    options: &EncoderOptions,
+    image: &ImageView<T>,
+    blocks: Option<&Vec<[f32; 64]>>,
+) {
+    let frame = Frame::new(image, options);
+    let restart_interval = effective_restart_interval(options, &frame);
+
+    append_header(out, mem, options, &frame, restart_interval);
+
+    let source: &dyn BlockSource = match blocks {
+        Some(blocks) => blocks,
+        None => image,
+    };
+
+    if let Some(predictor) = options.lossless {
+        let point_transform = options.point_transform;
+        lossless::append_scan(
+            out,
+            &frame,
+            image,
+            predictor,
+            point_transform,
+            restart_interval,
+        );
+    } else if options.progressive {
+        let mut coefficients = vec![];
+        for_each_mcu(&frame, source, 0..frame.mcu_rows(), quant, restart_interval, |mcu| {
+            coefficients.extend_from_slice(mcu)
+        });
+        if options.arithmetic_coding {
+            arithmetic::append_progressive_scans(
+                out,
+                &frame,
+                &coefficients,
+                restart_interval,
+            );
+        } else {
+            progressive::append_scans(out, &frame, &coefficients, restart_interval);
+        }
+    } else if options.arithmetic_coding {
+        arithmetic::append_sequential_scan(out, &frame, source, quant, restart_interval);
+    } else {
+        append_sequential_scan(out, mem, options, &frame, source, quant, restart_interval);
+    }
+
+    // EOI
+    out.write_u16(0xffd9);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quality {
+    Medium,
+    High,
+    Highest,
+    /// A quality between 1 (worst) and 100 (best), on the same scale as
+    /// libjpeg. Values outside that range are clamped.
+    Level(u8),
+}
+
+impl Quality {
+    /// Returns the quality as a number between 1 and 100. `Medium`, `High`
+    /// and `Highest` correspond to 50, 95 and 100.
+    pub fn value(&self) -> u8 {
+        match *self {
+            Quality::Medium => 50,
+            Quality::High => 95,
+            Quality::Highest => 100,
+            Quality::Level(q) => q.clamp(1, 100),
+        }
+    }
+}
+
+impl From<u8> for Quality {
+    fn from(quality: u8) -> Quality {
+        Quality::Level(quality)
+    }
+}
+
+/// The layout of a pixel in the input data. Every channel is one sample, and
+/// alpha channels are ignored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb,
+    Rgba,
+    Bgr,
+    Bgra,
+    Argb,
+    /// A single luma channel. Produces a grayscale JPEG.
+    Gray,
+    /// Luma followed by alpha. Produces a grayscale JPEG.
+    GrayAlpha,
+    /// Cyan, magenta, yellow and black, where 0 is no ink. Produces a CMYK or
+    /// YCCK JPEG.
+    Cmyk,
+}
+
+impl PixelFormat {
+    /// Picks the format the `num_components` argument of `encode_to_buffer`
+    /// stands for: gray, gray and alpha, RGB or RGBA.
+    fn from_num_components(num_components: i32) -> Result<PixelFormat, EncodeError> {
+        match num_components {
+            1 => Ok(PixelFormat::Gray),
+            2 => Ok(PixelFormat::GrayAlpha),
+            3 => Ok(PixelFormat::Rgb),
+            4 => Ok(PixelFormat::Rgba),
+            _ => Err(EncodeError::UnsupportedComponents(num_components)),
+        }
+    }
+
+    /// Returns the number of samples taken by one pixel, which is its size in
+    /// bytes for `u8` data.
+    pub fn bytes_per_pixel(&self) -> usize {
+        match *self {
+            PixelFormat::Gray => 1,
+            PixelFormat::GrayAlpha => 2,
+            PixelFormat::Rgb | PixelFormat::Bgr => 3,
+            PixelFormat::Rgba | PixelFormat::Bgra | PixelFormat::Argb | PixelFormat::Cmyk => 4,
+        }
+    }
+
+    /// Returns true for formats that produce a single-component JPEG.
+    pub fn is_grayscale(&self) -> bool {
+        matches!(*self, PixelFormat::Gray | PixelFormat::GrayAlpha)
+    }
+
+    // Sample offsets within a pixel of red, green and blue, or of cyan,
+    // magenta, yellow and black. Grayscale formats report the luma channel
+    // for all of them.
+    fn channel_offsets(&self) -> [usize; 4] {
+        match *self {
+            PixelFormat::Rgb | PixelFormat::Rgba => [0, 1, 2, 0],
+            PixelFormat::Bgr | PixelFormat::Bgra => [2, 1, 0, 0],
+            PixelFormat::Argb => [1, 2, 3, 0],
+            PixelFormat::Gray | PixelFormat::GrayAlpha => [0; 4],
+            PixelFormat::Cmyk => [0, 1, 2, 3],
+        }
+    }
+}
+
+/// The type of the channel values in the input. `u8` samples produce 8-bit
+/// JPEGs, and `u16` samples, which go up to 4095, produce 12-bit JPEGs.
+pub trait Sample: Copy + Sync {
+    /// Bits per sample in the JPEG: 8 or 12, or from 2 to 16 for lossless
+    /// JPEGs. Other precisions are refused with
+    /// `EncodeError::UnsupportedOptions`.
+    const PRECISION: u8;
+
+    /// Returns the value of the sample, which should fit in `PRECISION`
+    /// bits. Larger values are clamped to the largest that does.
+    fn to_u16(self) -> u16;
+}
+
+// The value of `sample`, clamped to what `T::PRECISION` bits hold, since
+// samples of other types may return more.
+fn sample_value<T: Sample>(sample: T) -> u16 {
+    let max = (1u32 << T::PRECISION.min(16)) - 1;
+    sample.to_u16().min(max as u16)
+}
+
+impl Sample for u8 {
+    const PRECISION: u8 = 8;
+
+    fn to_u16(self) -> u16 {
+        self as u16
+    }
+}
+
+impl Sample for u16 {
+    const PRECISION: u8 = 12;
+
+    fn to_u16(self) -> u16 {
+        self.min(0xfff)
+    }
+}
+
+/// Describes where the pixels of an image are in memory.
+///
+/// The view can be a sub-rectangle of a larger buffer, rows may be padded, and
+/// rows may be stored bottom-up, as in the output of `glReadPixels`.
+#[derive(Clone, Copy, Debug)]
+pub struct ImageView<'a, T: 'a = u8> {
+    pub data: &'a [T],
+    pub format: PixelFormat,
+    /// Size of the encoded image in pixels.
+    pub width: u32,
+    pub height: u32,
+    /// Distance in samples between the starts of two consecutive rows in
+    /// `data`.
+    pub stride: usize,
+    /// Position in pixels of the top-left corner of the image within `data`,
+    /// counted from the first sample. With `flipped`, `y` is still the first
+    /// row in memory, which holds the bottom row of the image.
+    pub x: usize,
+    pub y: usize,
+    /// Whether rows are stored bottom-up.
+    pub flipped: bool,
+}
+
+impl<'a, T: Sample> ImageView<'a, T> {
+    /// Returns a view of tightly packed, top-down rows covering all of `data`.
+    pub fn new(data: &'a [T], width: u32, height: u32, format: PixelFormat) -> ImageView<'a, T> {
+        ImageView {
+            data,
+            format,
+            width,
+            height,
+            stride: width as usize * format.bytes_per_pixel(),
+            x: 0,
+            y: 0,
+            flipped: false,
+        }
+    }
+
+    /* Checks that a JPEG can store the view, that its rows don't overlap and
+       that it doesn't reach past the end of `data`. Ends that don't fit in a
+       usize are reported as usize::MAX. */
+    fn check(&self) -> Result<(), EncodeError> {
+        check_dimensions(self.width as i64, self.height as i64)?;
+        let bytes_per_pixel = self.format.bytes_per_pixel();
+        let row_end = (self.x.checked_add(self.width as usize))
+            .and_then(|end| end.checked_mul(bytes_per_pixel))
+            .unwrap_or(usize::MAX);
+        if self.height > 1 && row_end > self.stride {
+            return Err(EncodeError::InvalidStride {
+                stride: self.stride,
+                row_len: row_end,
+            });
+        }
+        let rows_end = (self.y.checked_add(self.height as usize - 1))
+            .and_then(|rows| rows.checked_mul(self.stride))
+            .and_then(|start| start.checked_add(row_end))
+            .unwrap_or(usize::MAX);
+        if rows_end > self.data.len() {
+            return Err(EncodeError::BufferLength {
+                expected: rows_end,
+                actual: self.data.len(),
+            });
+        }
+        Ok(())
+    }
+
+    // Offset in `data` of pixel (x, y), with y counted from the top of
+    // the image.
+    fn offset(&self, x: usize, y: usize) -> usize {
+        let row = if self.flipped {
+            self.y + self.height as usize - 1 - y
+        } else {
+            self.y + y
+        };
+        row * self.stride + (self.x + x) * self.format.bytes_per_pixel()
+    }
+}
+
+/// How much the chroma (Cb and Cr) components are downsampled relative to luma.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Subsampling {
+    /// 4:4:4 - chroma at full resolution.
+    S444,
+    /// 4:2:2 - chroma at half horizontal resolution. 16x8 MCUs.
+    S422,
+    /// 4:2:0 - chroma at half horizontal and vertical resolution. 16x16 MCUs.
+    S420,
+    /// 4:4:0 - chroma at half vertical resolution. 8x16 MCUs.
+    S440,
+    /// 4:1:1 - chroma at a quarter of the horizontal resolution. 32x8 MCUs.
+    S411,
+}
+
+impl Subsampling {
+    /// Returns the horizontal and vertical sampling factors of the luma
+    /// component. The chroma components always use 1x1.
+    fn luma_factors(&self) -> (usize, usize) {
+        match *self {
+            Subsampling::S444 => (1, 1),
+            Subsampling::S422 => (2, 1),
+            Subsampling::S420 => (2, 2),
+            Subsampling::S440 => (1, 2),
+            Subsampling::S411 => (4, 1),
+        }
+    }
+}
+
+/// The predictors of lossless JPEG, from Table H.1 of the JPEG spec. Each
+/// sample is predicted from the samples to its left (a), above (b) and above
+/// left (c), and only the difference from the prediction is stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Predictor {
+    /// a
+    Left = 1,
+    /// b
+    Above,
+    /// c
+    AboveLeft,
+    /// a + b - c
+    Gradient,
+    /// a + (b - c) / 2
+    LeftGradient,
+    /// b + (a - c) / 2
+    AboveGradient,
+    /// (a + b) / 2
+    Average,
+}
+
+/// The size of a pixel, in the JFIF segment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Density {
+    /// Only the ratio of the width of a pixel to its height.
+    AspectRatio(u16, u16),
+    /// Pixels per inch, horizontally and vertically.
+    PerInch(u16, u16),
+    /// Pixels per centimeter, horizontally and vertically.
+    PerCentimeter(u16, u16),
+}
+
+/// What the JPEG says about the image besides its pixels.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    /// 96 DPI by default. Only grayscale and YCbCr JPEGs have a JFIF segment
+    /// to store it in; lossless RGB and four-component JPEGs leave it out.
+    pub density: Density,
+    /// A COM segment of up to 65533 bytes, or none. "Created by Tiny JPEG
+    /// Encoder" by default.
+    pub comment: Option<Vec<u8>>,
+}
+
+impl Default for Metadata {
+    fn default() -> Metadata {
+        Metadata {
+            density: Density::PerInch(96, 96),
+            comment: Some(b"Created by Tiny JPEG Encoder".to_vec()),
+        }
+    }
+}
+
+/// A quantization table: the step that each of the 64 DCT coefficients of a
+/// block is divided by. Larger steps give smaller files and coarser images.
+///
+/// Entries past 255 are written as 16 bits, which makes sequential 8-bit
+/// JPEGs extended rather than baseline.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QuantizationTable {
+    zigzag: [u16; QT_SIZE],
+}
+
+impl QuantizationTable {
+    /// Makes a table from its entries in natural order, row by row from the
+    /// lowest frequencies. Returns `None` if an entry is 0.
+    pub fn from_natural(values: &[u16; QT_SIZE]) -> Option<QuantizationTable> {
+        let mut zigzag = [0; QT_SIZE];
+        for i in 0..QT_SIZE {
+            zigzag[ZIG_ZAG[i]] = values[i];
+        }
+        QuantizationTable::from_zigzag(&zigzag)
+    }
+
+    /// Makes a table from its entries in zigzag order, the order JPEG files
+    /// store them in. Returns `None` if an entry is 0.
+    pub fn from_zigzag(values: &[u16; QT_SIZE]) -> Option<QuantizationTable> {
+        if values.contains(&0) {
+            return None;
+        }
+        Some(QuantizationTable { zigzag: *values })
+    }
+
+    /// Returns the entries in natural order.
+    pub fn natural(&self) -> [u16; QT_SIZE] {
+        let mut values = [0; QT_SIZE];
+        for i in 0..QT_SIZE {
+            values[i] = self.zigzag[ZIG_ZAG[i]];
+        }
+        values
+    }
+
+    /// Returns the entries in zigzag order.
+    pub fn zigzag(&self) -> [u16; QT_SIZE] {
+        self.zigzag
+    }
+}
+
+/// A Huffman table, as in a DHT segment: the number of codes of each length
+/// from 1 to 16 bits, and the symbols they code, shortest code first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HuffmanTable {
+    bits: [u8; 16],
+    values: Vec<u8>,
+}
+
+impl HuffmanTable {
+    /// Makes a table from its code counts and symbols. Returns `None` unless
+    /// there are as many symbols as codes, no symbol repeats, and the codes
+    /// fit in 16 bits without using a code of all ones, which JPEG forbids.
+    ///
+    /// The encoder only takes tables that code every symbol a frame can
+    /// produce, whether or not the image needs it: for a frame of `P` bits
+    /// per sample, DC tables need the categories 0 to `P + 3`, and AC tables
+    /// need EOB (0x00), ZRL (0xf0) and every run of 0 to 15 zeros with a size
+    /// of 1 to `P + 2`. Other tables give `EncodeError::InvalidTables`.
+    pub fn new(bits: [u8; 16], values: Vec<u8>) -> Option<HuffmanTable> {
+        let num_codes: usize = bits.iter().map(|&n| n as usize).sum();
+        if num_codes == 0 || num_codes != values.len() {
+            return None;
+        }
+        let mut seen = [false; 256];
+        for &v in values.iter() {
+            if seen[v as usize] {
+                return None;
+            }
+            seen[v as usize] = true;
+        }
+        // Codes are assigned in increasing order. After the codes of each
+        // length, the next free code must still be shorter than all ones.
+        let mut code = 0u32;
+        for (i, &n) in bits.iter().enumerate() {
+            code += n as u32;
+            if code >= 1 << (i + 1) {
+                return None;
+            }
+            code <<= 1;
+        }
+        Some(HuffmanTable { bits, values })
+    }
+
+    /// Returns the number of codes of each length.
+    pub fn bits(&self) -> &[u8; 16] {
+        &self.bits
+    }
+
+    /// Returns the symbols, shortest code first.
+    pub fn values(&self) -> &[u8] {
+        &self.values
+    }
+
+    // Whether there is a code for every DC difference category, or every AC
+    // run/size symbol, of a frame with `precision` bits per sample.
+    fn is_complete(&self, ac: bool, precision: u8) -> bool {
+        let mut symbols = vec![];
+        if ac {
+            // EOB and ZRL, then up to 15 zeros before a coefficient.
+            symbols.extend_from_slice(&[0x00, 0xf0]);
+            for run in 0..16 {
+                for size in 1..=precision + 2 {
+                    symbols.push((run << 4) | size);
+                }
+            }
+        } else {
+            symbols.extend(0..=precision + 3);
+        }
+        symbols.iter().all(|s| self.values.contains(s))
+    }
+}
+
+/// A size limit for `encode_to_size`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SizeTarget {
+    /// The most bytes the JPEG may take.
+    Bytes(usize),
+    /// The most bits the JPEG may take per pixel of the image.
+    BitsPerPixel(f32),
+}
+
+/// A fidelity to the source for `encode_to_fidelity` to reach.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FidelityTarget {
+    /// The least peak signal-to-noise ratio over all channels, in dB.
+    Psnr(f64),
+    /// The least structural similarity, from 0 to 1, averaged over 8x8
+    /// windows and over the channels.
+    Ssim(f64),
+}
+
+/// Why an image couldn't be encoded.
+#[derive(Debug)]
+pub enum EncodeError {
+    /// A width or height of 0, below 0 or past 65535, which JPEG can't store.
+    InvalidDimensions { width: i64, height: i64 },
+    /// A `num_components` other than 1, 2, 3 or 4.
+    UnsupportedComponents(i32),
+    /// Image data of the wrong length: shorter than the image, or, for
+    /// `StreamEncoder`, rows that aren't whole or that the image doesn't have.
+    /// Lengths are in samples.
+    BufferLength { expected: usize, actual: usize },
+    /// Rows of an `ImageView` that overlap, with a stride shorter than a row.
+    InvalidStride { stride: usize, row_len: usize },
+    /// Custom tables that are too many, that a component refers to but that
+    /// aren't given, or that can't code every value of the frame.
+    InvalidTables(&'static str),
+    /// Options that can't be used together, or with `StreamEncoder`.
+    UnsupportedOptions(&'static str),
+    /// An error from the sink the JPEG is written to.
+    Io(io::Error),
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EncodeError::InvalidDimensions { width, height } => {
+                write!(f, "invalid image dimensions {}x{}", width, height)
+            }
+            EncodeError::UnsupportedComponents(n) => {
+                write!(f, "unsupported number of components: {}", n)
+            }
+            EncodeError::BufferLength { expected, actual } => {
+                write!(f, "image data of {} samples, expected {}", actual, expected)
+            }
+            EncodeError::InvalidStride { stride, row_len } => {
+                write!(f, "stride of {} samples, shorter than a row of {}", stride, row_len)
+            }
+            EncodeError::InvalidTables(why) => write!(f, "invalid tables: {}", why),
+            EncodeError::UnsupportedOptions(why) => write!(f, "unsupported options: {}", why),
+            EncodeError::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for EncodeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            EncodeError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for EncodeError {
+    fn from(e: io::Error) -> EncodeError {
+        EncodeError::Io(e)
+    }
+}
+
+// Checks that a JPEG can store a `width` x `height` image.
+fn check_dimensions(width: i64, height: i64) -> Result<(), EncodeError> {
+    if width < 1 || height < 1 || width > 0xffff || height > 0xffff {
+        return Err(EncodeError::InvalidDimensions { width, height });
+    }
+    Ok(())
+}
+
+/// Settings for `encode_to_buffer_with_options`.
+#[derive(Clone, Debug)]
+pub struct EncoderOptions {
+    pub quality: Quality,
+    pub subsampling: Subsampling,
+    /// Build Huffman tables fitted to the image instead of using the example
+    /// tables from the JPEG spec. This takes a second pass over the image and
+    /// keeps its quantized coefficients in memory, but gives smaller files.
+    pub optimize_huffman: bool,
+    /// Write a progressive JPEG, which decoders can show at low resolution
+    /// before all of it has arrived. Progressive JPEGs always use optimized
+    /// Huffman tables.
+    pub progressive: bool,
+    /// Number of MCUs between restart markers, or 0 for none. Each interval
+    /// can be decoded on its own, so a corrupted byte only damages the rest
+    /// of its interval. Lossless JPEGs round it up to whole rows of pixels.
+    pub restart_interval: u16,
+    /// Code the image with the arithmetic coder instead of Huffman tables.
+    /// This gives smaller files than optimized Huffman tables, but not every
+    /// decoder supports it. `optimize_huffman` has no effect when it is set.
+    pub arithmetic_coding: bool,
+    /// Write a lossless JPEG with the given predictor. The samples are stored
+    /// exactly, with no color conversion or subsampling, so `quality`,
+    /// `subsampling`, `optimize_huffman`, `progressive` and
+    /// `arithmetic_coding` have no effect. Many decoders can't read lossless
+    /// JPEGs.
+    pub lossless: Option<Predictor>,
+    /// Number of low bits dropped from every sample of a lossless JPEG, which
+    /// makes it smaller but no longer exact. 0 by default.
+    pub point_transform: u8,
+    /// Store `PixelFormat::Cmyk` input as YCCK, which is to CMYK what YCbCr
+    /// is to RGB, instead of as plain CMYK. This usually gives smaller files.
+    /// Lossless JPEGs are always plain CMYK.
+    pub ycck: bool,
+    /// Quantization tables to use instead of the example tables from the
+    /// JPEG spec scaled for `quality`, in DQT slots 0 to 3. Empty by default,
+    /// and at most four.
+    pub quantization_tables: Vec<QuantizationTable>,
+    /// The slot in `quantization_tables` of each component, in frame order.
+    /// By default, luma and the CMYK channels use slot 0 and chroma slot 1,
+    /// or slot 0 if there is only one table.
+    pub component_quantization: Option<[u8; 4]>,
+    /// Huffman tables to use instead of the example tables from the JPEG
+    /// spec, as DC and AC table pairs in slots 0 to 3. Each table must code
+    /// every symbol of its class, as `HuffmanTable::new` describes. Only
+    /// sequential Huffman-coded JPEGs without `optimize_huffman` use them.
+    /// Empty by default, and at most four.
+    pub huffman_tables: Vec<(HuffmanTable, HuffmanTable)>,
+    /// The slot in `huffman_tables` of each component, in frame order. By
+    /// default, luma and the CMYK channels use slot 0 and chroma slot 1, or
+    /// slot 0 if there is only one pair.
+    pub component_huffman: Option<[u8; 4]>,
+    /// Trellis quantization with the given lambda. Instead of rounding every
+    /// coefficient, the encoder picks the values that minimize their size in
+    /// bits plus lambda times their squared error in quantization steps, as
+    /// mozjpeg does. Smaller values give smaller files with more artifacts.
+    /// Lambda must be finite and positive. None by default.
+    pub trellis: Option<f32>,
+    /// Convert colors, transform and quantize with integer arithmetic only,
+    /// as libjpeg does with its accurate integer DCT, instead of floating
+    /// point. The output is then the same on every platform and with any
+    /// compiler flags, and needs no FPU. Trellis quantization still weighs
+    /// its choices in floating point. Off by default.
+    pub fixed_point: bool,
+    /// Code sequential Huffman-coded JPEGs in bands of rows on this many
+    /// threads, or on one thread per CPU with `Some(0)`. With more than one
+    /// thread and more than one row of MCUs, the bands are separated by
+    /// restart markers, so `restart_interval` is rounded up to whole rows of
+    /// MCUs, or one row if it is 0. The output is then the same with any
+    /// number of threads above one. Progressive, arithmetic-coded and
+    /// lossless JPEGs ignore it. None by default, which codes on the calling
+    /// thread.
+    pub threads: Option<usize>,
+    /// The pixel density and the comment written before the frame.
+    pub metadata: Metadata,
+}
+
+impl Default for EncoderOptions {
+    fn default() -> EncoderOptions {
+        EncoderOptions {
+            quality: Quality::Highest,
+            subsampling: Subsampling::S444,
+            optimize_huffman: false,
+            progressive: false,
+            restart_interval: 0,
+            arithmetic_coding: false,
+            lossless: None,
+            point_transform: 0,
+            ycck: false,
+            quantization_tables: vec![],
+            component_quantization: None,
+            huffman_tables: vec![],
+            component_huffman: None,
+            trellis: None,
+            fixed_point: false,
+            threads: None,
+            metadata: Metadata::default(),
+        }
+    }
+}
+
+/// Takes bitmap data and writes a JPEG-encoded image to disk at the highest
+/// quality.
+pub fn encode_to_file(
+    dest: &Path,
+    w: i32,
+    h: i32,
+    num_components: i32,
+    data: &[u8],
+) -> Result<(), EncodeError> {
+    encode_to_file_at_quality(dest, Quality::Highest, w, h, num_components, data)
+}
+
+/// Takes bitmap data and writes a JPEG-encoded image to disk at the specified
+/// quality.
+pub fn encode_to_file_at_quality(
+    dest: &Path,
+    quality: Quality,
+    w: i32,
+    h: i32,
+    num_components: i32,
+    data: &[u8],
+) -> Result<(), EncodeError> {
+    let format = PixelFormat::from_num_components(num_components)?;
+    let image = view_of(data, w, h, format)?;
+    Encoder::new().quality(quality).encode_to_file(dest, &image)
+}
+
+/// Returns a JPEG-encoded buffer, given bitmap data
+pub fn encode_to_buffer(
+    quality: Quality,
+    w: i32,
+    h: i32,
+    num_components: i32,
+    data: &[u8],
+) -> Result<Vec<u8>, EncodeError> {
+    let format = PixelFormat::from_num_components(num_components)?;
+    Encoder::new().quality(quality).encode(&view_of(data, w, h, format)?)
+}
+
+/// Returns a JPEG-encoded buffer, given bitmap data in the given pixel format
+/// and encoder settings
+pub fn encode_to_buffer_with_options(
+    options: &EncoderOptions,
+    w: i32,
+    h: i32,
+    format: PixelFormat,
+    data: &[u8],
+) -> Result<Vec<u8>, EncodeError> {
+    Encoder::from(options.clone()).encode(&view_of(data, w, h, format)?)
+}
+
+// A view of the tightly packed rows of a `w` x `h` image, the way the
+// functions that take `i32` dimensions give them.
+fn view_of(
+    data: &[u8],
+    w: i32,
+    h: i32,
+    format: PixelFormat,
+) -> Result<ImageView<'_>, EncodeError> {
+    check_dimensions(w as i64, h as i64)?;
+    Ok(ImageView::new(data, w as u32, h as u32, format))
+}
+
+/// Returns a JPEG-encoded buffer, given a view of bitmap data and encoder
+/// settings. `u16` data gives a 12-bit JPEG.
+pub fn encode_image<T: Sample>(
+    options: &EncoderOptions,
+    image: &ImageView<T>,
+) -> Result<Vec<u8>, EncodeError> {
+    Encoder::from(options.clone()).encode(image)
+}
+
+/// Writes a JPEG-encoded image to `sink`, given a view of bitmap data and
+/// encoder settings, as `Encoder::encode_to_writer` does.
+pub fn encode_to_writer<W: Write, T: Sample>(
+    sink: W,
+    options: &EncoderOptions,
+    image: &ImageView<T>,
+) -> Result<(), EncodeError> {
+    Encoder::from(options.clone()).encode_to_writer(sink, image)
+}
+
+/// Returns the JPEG-encoded buffer at the highest quality that fits `target`,
+/// and that quality, or None if even quality 1 doesn't fit. The image is
+/// converted and transformed once, and only quantization and entropy coding
+/// run again for each quality tried. Sizes don't always grow with quality,
+/// so this is the best quality that a binary search finds. Lossless JPEGs
+/// and custom quantization tables don't depend on `options.quality`, and are
+/// encoded once.
+pub fn encode_to_size<T: Sample>(
+    options: &EncoderOptions,
+    image: &ImageView<T>,
+    target: SizeTarget,
+) -> Result<Option<(Vec<u8>, Quality)>, EncodeError> {
+    image.check()?;
+    let max_bytes = match target {
+        SizeTarget::Bytes(bytes) => bytes,
+        SizeTarget::BitsPerPixel(bpp) => {
+            (bpp as f64 * image.width as f64 * image.height as f64 / 8.0) as usize
+        }
+    };
+
+    if options.lossless.is_some() || !options.quantization_tables.is_empty() {
+        let jpeg = encode_blocks(options, image, None)?;
+        return Ok(if jpeg.len() <= max_bytes { Some((jpeg, options.quality)) } else { None });
+    }
+
+    let frame = Frame::new(image, options);
+    let mut blocks = vec![];
+    let kernels = simd::Kernels::detect();
+    image.for_each_transformed_mcu(&frame, kernels, 0..frame.mcu_rows(), &mut |mcu| {
+        blocks.extend_from_slice(mcu)
+    });
+
+    let mut options = options.clone();
+    let mut best = None;
+    let (mut low, mut high) = (1u8, 100u8);
+    while low <= high {
+        let quality = low + (high - low) / 2;
+        options.quality = Quality::Level(quality);
+        let jpeg = encode_blocks(&options, image, Some(&blocks))?;
+        if jpeg.len() <= max_bytes {
+            best = Some((jpeg, options.quality));
+            low = quality + 1;
+        } else if quality == 1 {
+            break;
+        } else {
+            high = quality - 1;
+        }
+    }
+    Ok(best)
+}
+
+/// Returns the JPEG-encoded buffer at the lowest quality that reaches
+/// `target`, that quality, and the PSNR or SSIM it reached, or None if even
+/// quality 100 falls short. Each quality tried is decoded and compared with
+/// the source, so this takes several times as long as `encode_image`. Like
+/// `encode_to_size`, it transforms the image once and searches the quality
+/// scale by bisection. Lossless JPEGs and custom quantization tables are
+/// encoded and measured once.
+pub fn encode_to_fidelity<T: Sample>(
+    options: &EncoderOptions,
+    image: &ImageView<T>,
+    target: FidelityTarget,
+) -> Result<Option<(Vec<u8>, Quality, f64)>, EncodeError> {
+    image.check()?;
+    let frame = Frame::new(image, options);
+    let source = metric::source_planes(image, &frame);
+    let measure = |decoded: &metric::Planes| match target {
+        FidelityTarget::Psnr(_) => metric::psnr(&source, decoded, frame.precision),
+        FidelityTarget::Ssim(_) => {
+            metric::ssim(&source, decoded, frame.width, frame.height, frame.precision)
+        }
+    };
+    let least = match target {
+        FidelityTarget::Psnr(least) | FidelityTarget::Ssim(least) => least,
+    };
+
+    let mut blocks = vec![];
+    if options.lossless.is_none() {
+        let kernels = simd::Kernels::detect();
+        image.for_each_transformed_mcu(&frame, kernels, 0..frame.mcu_rows(), &mut |mcu| {
+            blocks.extend_from_slice(mcu)
+        });
+    }
+    // Decodes the quantized blocks of `options`.
+    let decode = |options: &EncoderOptions| -> Result<metric::Planes, EncodeError> {
+        let mem = State::new(options, frame.precision)?;
+        let restart_interval = effective_restart_interval(options, &frame);
+        let quant = Quantizer::new(&mem, options);
+        let mut coefficients = vec![];
+        for_each_mcu(&frame, &blocks, 0..frame.mcu_rows(), &quant, restart_interval, |mcu| {
+            coefficients.extend_from_slice(mcu)
+        });
+        Ok(metric::decode(&frame, &mem.qt, &coefficients))
+    };
+
+    if options.lossless.is_some() || !options.quantization_tables.is_empty() {
+        let decoded = match options.lossless {
+            Some(_) => metric::lossless_planes(&source, &frame, options.point_transform),
+            None => decode(options)?,
+        };
+        let reached = measure(&decoded);
+        if reached < least {
+            return Ok(None);
+        }
+        return Ok(Some((encode_blocks(options, image, None)?, options.quality, reached)));
+    }
+
+    let mut options = options.clone();
+    let mut best = None;
+    let (mut low, mut high) = (1u8, 100u8);
+    while low <= high {
+        let quality = low + (high - low) / 2;
+        options.quality = Quality::Level(quality);
+        let reached = measure(&decode(&options)?);
+        if reached >= least {
+            best = Some((options.quality, reached));
+            if quality == 1 {
+                break;
+            }
+            high = quality - 1;
+        } else {
+            low = quality + 1;
+        }
+    }
+    match best {
+        Some((quality, reached)) => {
+            options.quality = quality;
+            Ok(Some((encode_blocks(&options, image, Some(&blocks))?, quality, reached)))
+        }
+        None => Ok(None),
+    }
+}
+
+// Encodes `image`, or its transformed blocks if they are given.
+fn encode_blocks<T: Sample>(
+    options: &EncoderOptions,
+    image: &ImageView<T>,
+    blocks: Option<&Vec<[f32; 64]>>,
+) -> Result<Vec<u8>, EncodeError> {
+    let mem = State::new(options, T::PRECISION)?;
+    let quant = Quantizer::new(&mem, options);
+    let mut out = Writer::buffer();
+    encode_main(&mut out, &mem, &quant, options, image, blocks);
+    Ok(out.into_bytes())
+}
+
+impl State {
+    // The quantization and Huffman tables that `options` ask for, for a frame
+    // with `precision` bits per sample, or why `options` can't encode such a
+    // frame.
+    fn new(options: &EncoderOptions, precision: u8) -> Result<State, EncodeError> {
+        // DCT-based frames have 8 or 12 bits per sample, and lossless ones 2
+        // to 16.
+        let supported = match options.lossless {
+            Some(_) => (2..=16).contains(&precision),
+            None => precision == 8 || precision == 12,
+        };
+        if !supported {
+            return Err(EncodeError::UnsupportedOptions("sample precision"));
+        }
+        if options.lossless.is_some() && options.point_transform >= precision {
+            return Err(EncodeError::UnsupportedOptions("point transform of every bit"));
+        }
+        if options.trellis.is_some_and(|lambda| !lambda.is_finite() || lambda <= 0.0) {
+            return Err(EncodeError::UnsupportedOptions("trellis lambda"));
+        }
+        if options.metadata.comment.as_ref().is_some_and(|c| c.len() > 0xfffd) {
+            return Err(EncodeError::UnsupportedOptions("comment longer than 65533 bytes"));
+        }
+
+        let mut mem = State {
+            ehuffsize: vec![],
+            ehuffcode: vec![],
+            ht_bits: vec![
+                DEFAULT_HT_LUMA_DC_LEN,
+                DEFAULT_HT_LUMA_AC_LEN,
+                DEFAULT_HT_CHROMA_DC_LEN,
+                DEFAULT_HT_CHROMA_AC_LEN,
+            ],
+            ht_vals: vec![
+                DEFAULT_HT_LUMA_DC.to_vec(),
+                DEFAULT_HT_LUMA_AC.to_vec(),
+                DEFAULT_HT_CHROMA_DC.to_vec(),
+                DEFAULT_HT_CHROMA_AC.to_vec(),
+            ],
+            qt: vec![],
+            pqt: vec![],
+            divisors: vec![],
+        };
+
+        if options.quantization_tables.is_empty() {
+            // The Annex K tables, scaled in natural order and stored in
+            // zigzag order. 12-bit frames have 16-bit quantization tables.
+            let quality = options.quality.value();
+            let max = if precision > 8 { 32767 } else { 255 };
+            for table in [&DEFAULT_QT_LUMA_FROM_SPEC, &DEFAULT_QT_CHROMA_FROM_SPEC] {
+                let mut qt = [1; QT_SIZE];
+                scale_qt(&mut qt, table, quality, max);
+                mem.qt.push(QuantizationTable::from_natural(&qt).unwrap().zigzag);
+            }
+        } else {
+            if options.quantization_tables.len() > 4 {
+                return Err(EncodeError::InvalidTables("more than 4 quantization tables"));
+            }
+            mem.qt = options.quantization_tables.iter().map(|t| t.zigzag).collect();
+        }
+        if let Some(slots) = options.component_quantization {
+            if slots.iter().any(|&slot| slot as usize >= mem.qt.len()) {
+                return Err(EncodeError::InvalidTables("missing quantization table"));
+            }
+        }
+
+        if !options.huffman_tables.is_empty() {
+            // Every table must code every symbol that the frame can produce.
+            if options.huffman_tables.len() > 4 {
+                return Err(EncodeError::InvalidTables("more than 4 Huffman tables"));
+            }
+            mem.ht_bits.clear();
+            mem.ht_vals.clear();
+            for (dc, ac) in options.huffman_tables.iter() {
+                if !dc.is_complete(false, precision) || !ac.is_complete(true, precision) {
+                    return Err(EncodeError::InvalidTables("incomplete Huffman table"));
+                }
+                for table in [dc, ac].iter() {
+                    mem.ht_bits.push(table.bits);
+                    mem.ht_vals.push(table.values.clone());
+                }
+            }
+        }
+        if let Some(slots) = options.component_huffman {
+            if slots.iter().any(|&slot| slot as usize >= options.huffman_tables.len()) {
+                return Err(EncodeError::InvalidTables("missing Huffman table"));
+            }
+        }
+
+        huff_expand(&mut mem);
+        mem.scale_for_dct(options.fixed_point);
+        Ok(mem)
+    }
+
+    // Fills `pqt`, and `divisors` if the frames are `fixed_point`, from `qt`.
+    #[allow(clippy::excessive_precision, clippy::needless_range_loop)]
+    fn scale_for_dct(&mut self, fixed_point: bool) {
+        let mut pqt = vec![[0f32; 64]; self.qt.len()];
+
+        /* For float AA&N IDCT method, divisors are equal to quantization
+           coefficients scaled by scalefactor[row]*scalefactor[col], where
+             scalefactor[0] = 1
+             scalefactor[k] = cos(k*PI/16) * sqrt(2)    for k=1..7
+           We apply a further scale factor of 8.
+           What's actually stored is 1/divisor so that the inner loop can
+           use a multiplication rather than a division. */
+        const AAN_SCALES: [f32; 8] = [
+            1.0, 1.387039845, 1.306562965, 1.175875602,
+            1.0, 0.785694958, 0.541196100, 0.275899379,
+        ];
+
+        // Build (de)quantization tables
+        for (table, qt) in pqt.iter_mut().zip(self.qt.iter()) {
+            for y in 0..8 {
+                for x in 0..8 {
+                    let i = y * 8 + x;
+                    let q = qt[ZIG_ZAG[i]] as f32;
+                    table[i] = 1.0 / (8.0 * AAN_SCALES[x] * AAN_SCALES[y] * q);
+                }
+            }
+        }
+
+        // The integer DCT scales every coefficient up by 8.
+        let mut divisors = vec![];
+        if fixed_point {
+            for (table, qt) in pqt.iter_mut().zip(self.qt.iter()) {
+                let mut divisor = [0i32; 64];
+                for i in 0..64 {
+                    divisor[i] = 8 * qt[ZIG_ZAG[i]] as i32;
+                    table[i] = 1.0 / divisor[i] as f32;
+                }
+                divisors.push(divisor);
+            }
+        }
+
+        self.pqt = pqt;
+        self.divisors = divisors;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::marker::PhantomData;
+    use std::sync::MutexGuard;
+
+    // Samples of `P` bits.
+    #[derive(Clone, Copy)]
+    struct Bits<const P: u8>(u16);
+
+    impl<const P: u8> Sample for Bits<P> {
+        const PRECISION: u8 = P;
+
+        fn to_u16(self) -> u16 {
+            self.0
+        }
+    }
+
+    // Returns the offset of the first 0xff<marker> pair in `jpeg`.
+    fn find_marker(jpeg: &[u8], marker: u8) -> Option<usize> {
+        jpeg.windows(2).position(|w| w[0] == 0xff && w[1] == marker)
+    }
+
+    // Returns the samples of a `w` x `h` RGB image of gradients with some
+    // noise on top.
+    fn test_image(w: u32, h: u32) -> Vec<u8> {
+        let row = w * 3;
+        (0..row * h).map(|i| ((i % row) * 2 + (i / row) * 3 + (i * 37) % 23) as u8).collect()
+    }
+
+    #[test]
+    fn white_texture() {
+        let dest = env::temp_dir().join("tiny-jpeg-white-texture.jpg");
+        const W: i32 = 4000;
+        const H: i32 = 2000;
+        const C: i32 = 4;
+        let data = vec![255u8; (W * H * C) as usize];
+        assert!(encode_to_file(&dest, W, H, C, &data).is_ok());
+    }
+
+    #[test]
+    fn quality_scaling() {
+        let mut qt = [0u16; QT_SIZE];
+        scale_qt(&mut qt, &DEFAULT_QT_LUMA_FROM_SPEC, Quality::Medium.value(), 255);
+        assert!((0..QT_SIZE).all(|i| qt[i] == DEFAULT_QT_LUMA_FROM_SPEC[i] as u16));
+        scale_qt(&mut qt, &DEFAULT_QT_LUMA_FROM_SPEC, Quality::Highest.value(), 255);
+        assert!(qt.iter().all(|&q| q == 1));
+        scale_qt(&mut qt, &DEFAULT_QT_LUMA_FROM_SPEC, 1, 255);
+        assert!(qt.iter().all(|&q| q == 255));
+        // 16-bit tables keep the larger values.
+        scale_qt(&mut qt, &DEFAULT_QT_LUMA_FROM_SPEC, 1, 32767);
+        assert_eq!(qt[0], 16 * 50);
+        // Lower quality never produces a finer table.
+        let mut coarser = [0u16; QT_SIZE];
+        scale_qt(&mut qt, &DEFAULT_QT_LUMA_FROM_SPEC, 75, 255);
+        scale_qt(&mut coarser, &DEFAULT_QT_LUMA_FROM_SPEC, 25, 255);
+        assert!((0..QT_SIZE).all(|i| coarser[i] >= qt[i]));
+    }
+
+    #[test]
+    fn default_quantization_tables() {
+        // The tables libjpeg writes at quality 50 and 75, in zigzag order.
+        const LUMA_50: [u8; QT_SIZE] = [
+            16, 11, 12, 14, 12, 10, 16, 14, 13, 14, 18, 17, 16, 19, 24, 40,
+            26, 24, 22, 22, 24, 49, 35, 37, 29, 40, 58, 51, 61, 60, 57, 51,
+            56, 55, 64, 72, 92, 78, 64, 68, 87, 69, 55, 56, 80, 109, 81, 87,
+            95, 98, 103, 104, 103, 62, 77, 113, 121, 112, 100, 120, 92, 101, 103, 99,
+        ];
+        const CHROMA_50: [u8; 15] = [17, 18, 18, 24, 21, 24, 47, 26, 26, 47, 99, 66, 56, 66, 99];
+        const LUMA_75: [u8; QT_SIZE] = [
+            8, 6, 6, 7, 6, 5, 8, 7, 7, 7, 9, 9, 8, 10, 12, 20,
+            13, 12, 11, 11, 12, 25, 18, 19, 15, 20, 29, 26, 31, 30, 29, 26,
+            28, 28, 32, 36, 46, 39, 32, 34, 44, 35, 28, 28, 40, 55, 41, 44,
+            48, 49, 52, 52, 52, 31, 39, 57, 61, 56, 50, 60, 46, 51, 52, 50,
+        ];
+        const CHROMA_75: [u8; 15] = [9, 9, 9, 12, 11, 12, 24, 13, 13, 24, 50, 33, 28, 33, 50];
+
+        let data = test_image(16, 16);
+        let cases = [(50, LUMA_50, CHROMA_50, 99), (75, LUMA_75, CHROMA_75, 50)];
+        for (quality, luma, chroma, rest) in cases {
+            let jpeg = encode_to_buffer(Quality::Level(quality), 16, 16, 3, &data).unwrap();
+            let dqt = find_marker(&jpeg, 0xdb).unwrap();
+            assert_eq!(&jpeg[dqt + 2..dqt + 5], &[0, 67, 0]);
+            assert_eq!(jpeg[dqt + 5..dqt + 69], luma);
+            let dqt = dqt + 69;
+            assert_eq!(&jpeg[dqt..dqt + 5], &[0xff, 0xdb, 0, 67, 1]);
+            assert_eq!(jpeg[dqt + 5..dqt + 20], chroma);
+            assert!(jpeg[dqt + 20..dqt + 69].iter().all(|&q| q == rest));
+        }
+    }
+
+    #[test]
+    fn subsampling_factors() {
+        const W: i32 = 37;
+        const H: i32 = 21;
+        let data = vec![100u8; (W * H * 3) as usize];
+        let modes = [
+            (Subsampling::S444, 0x11),
+            (Subsampling::S422, 0x21),
+            (Subsampling::S420, 0x22),
+            (Subsampling::S440, 0x12),
+            (Subsampling::S411, 0x41),
+        ];
+        for &(subsampling, factors) in modes.iter() {
+            let options = EncoderOptions {
+                subsampling,
+                ..EncoderOptions::default()
+            };
+            let jpeg =
+                encode_to_buffer_with_options(&options, W, H, PixelFormat::Rgb, &data).unwrap();
+            let sof = find_marker(&jpeg, 0xc0).unwrap();
+            // Marker, length, precision, height, width, count, then the
+            // first component's id and sampling factors.
+            assert_eq!(jpeg[sof + 11], factors);
+            assert_eq!(jpeg[sof + 14], 0x11);
+            assert_eq!(&jpeg[jpeg.len() - 2..], &[0xff, 0xd9]);
+        }
+    }
+
+    #[test]
+    fn grayscale() {
+        const W: i32 = 19;
+        const H: i32 = 11;
+        let data: Vec<u8> = (0..W * H).map(|i| (i * 7) as u8).collect();
+        let jpeg = encode_to_buffer(Quality::High, W, H, 1, &data).unwrap();
+        let sof = find_marker(&jpeg, 0xc0).unwrap();
+        assert_eq!(jpeg[sof + 9], 1);
+        let sos = find_marker(&jpeg, 0xda).unwrap();
+        assert_eq!(jpeg[sos + 4], 1);
+        // Only the luma quantization and Huffman tables are written.
+        assert_eq!(jpeg.windows(2).filter(|w| w == &[0xff, 0xdb]).count(), 1);
+        assert_eq!(jpeg.windows(2).filter(|w| w == &[0xff, 0xc4]).count(), 2);
+    }
+
+    #[test]
+    fn pixel_formats() {
+        const W: i32 = 13;
+        const H: i32 = 9;
+        let rgb: Vec<u8> = (0..W * H * 3).map(|i| (i * 31) as u8).collect();
+        let expected = encode_to_buffer(Quality::High, W, H, 3, &rgb).unwrap();
+        let swizzles: [(PixelFormat, &[Option<usize>]); 4] = [
+            (PixelFormat::Rgba, &[Some(0), Some(1), Some(2), None]),
+            (PixelFormat::Bgr, &[Some(2), Some(1), Some(0)]),
+            (PixelFormat::Bgra, &[Some(2), Some(1), Some(0), None]),
+            (PixelFormat::Argb, &[None, Some(0), Some(1), Some(2)]),
+        ];
+        let options = EncoderOptions {
+            quality: Quality::High,
+            ..EncoderOptions::default()
+        };
+        for &(format, layout) in swizzles.iter() {
+            let mut data = vec![];
+            for px in rgb.chunks(3) {
+                data.extend(layout.iter().map(|c| c.map_or(0x55, |c| px[c])));
+            }
+            let jpeg = encode_to_buffer_with_options(&options, W, H, format, &data).unwrap();
+            assert!(jpeg == expected, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn strided_flipped_view() {
+        const W: usize = 21;
+        const H: usize = 10;
+        let rgb: Vec<u8> = (0..W * H * 3).map(|i| (i * 13) as u8).collect();
+        let options = EncoderOptions::default();
+        let packed = ImageView::new(&rgb, W as u32, H as u32, PixelFormat::Rgb);
+        let expected = encode_image(&options, &packed).unwrap();
+
+        // Place the image bottom-up at (3, 2) in a larger buffer with padded
+        // rows.
+        let stride = (W + 5) * 3 + 2;
+        let mut buffer = vec![0xaau8; stride * (H + 4)];
+        for row in 0..H {
+            let dst = (2 + H - 1 - row) * stride + 3 * 3;
+            buffer[dst..dst + W * 3].copy_from_slice(&rgb[row * W * 3..(row + 1) * W * 3]);
+        }
+        let view = ImageView {
+            stride,
+            x: 3,
+            y: 2,
+            flipped: true,
+            ..ImageView::new(&buffer, W as u32, H as u32, PixelFormat::Rgb)
+        };
+        assert!(encode_image(&options, &view).unwrap() == expected);
+    }
+
+    #[test]
+    fn optimal_huffman_table_limits() {
+        // Fibonacci counts give the deepest possible tree.
+        let mut freq = [0u32; 257];
+        let (mut a, mut b) = (1u32, 1u32);
+        for f in freq[..40].iter_mut() {
+            *f = a;
+            let next = a + b;
+            a = b;
+            b = next;
+        }
+        let (bits, vals) = huff_optimal_table(&freq);
+        assert_eq!(vals.len(), 40);
+        assert_eq!(bits.iter().map(|&b| b as usize).sum::<usize>(), 40);
+        // The code space must not be full, so the all-ones code is unused.
+        let kraft: u32 = (0..16).map(|i| (bits[i] as u32) << (15 - i)).sum();
+        assert!(kraft < 1 << 16);
+        // The most frequent symbols get the shortest codes.
+        assert!(vals[0] >= 38 && vals[1] >= 38);
+        assert!(vals[36..].iter().all(|&v| v <= 3));
+    }
+
+    #[test]
+    fn optimized_huffman_is_smaller() {
+        const W: i32 = 64;
+        const H: i32 = 48;
+        let data: Vec<u8> = (0..W * H * 3).map(|i| ((i * i) % 251) as u8).collect();
+        let mut options = EncoderOptions {
+            quality: Quality::Level(80),
+            ..EncoderOptions::default()
+        };
+        let fixed = encode_to_buffer_with_options(&options, W, H, PixelFormat::Rgb, &data).unwrap();
+        options.optimize_huffman = true;
+        let optimized =
+            encode_to_buffer_with_options(&options, W, H, PixelFormat::Rgb, &data).unwrap();
+        assert!(optimized.len() < fixed.len());
+        assert_eq!(&optimized[optimized.len() - 2..], &[0xff, 0xd9]);
+    }
+
+    #[test]
+    fn progressive_scans() {
+        const W: i32 = 45;
+        const H: i32 = 29;
+        let data: Vec<u8> = (0..W * H * 3).map(|i| ((i * i) % 253) as u8).collect();
+        let options = EncoderOptions {
+            quality: Quality::Level(85),
+            subsampling: Subsampling::S420,
+            progressive: true,
+            ..EncoderOptions::default()
+        };
+        let jpeg = encode_to_buffer_with_options(&options, W, H, PixelFormat::Rgb, &data).unwrap();
+        assert!(find_marker(&jpeg, 0xc2).is_some());
+        assert!(find_marker(&jpeg, 0xc0).is_none());
+        let scans = jpeg.windows(2).filter(|w| w == &[0xff, 0xda]).count();
+        assert_eq!(scans, progressive::default_scans(3, true).len());
+        assert_eq!(&jpeg[jpeg.len() - 2..], &[0xff, 0xd9]);
+
+        let gray = encode_to_buffer_with_options(&options, W, H, PixelFormat::Gray, &data).unwrap();
+        let scans = gray.windows(2).filter(|w| w == &[0xff, 0xda]).count();
+        assert_eq!(scans, progressive::default_scans(1, false).len());
+    }
+
+    #[test]
+    fn restart_markers() {
+        const W: i32 = 40;
+        const H: i32 = 24;
+        let data: Vec<u8> = (0..W * H * 3).map(|i| ((i * 3) % 256) as u8).collect();
+        let options = EncoderOptions {
+            restart_interval: 4,
+            ..EncoderOptions::default()
+        };
+        let jpeg = encode_to_buffer_with_options(&options, W, H, PixelFormat::Rgb, &data).unwrap();
+        let dri = find_marker(&jpeg, 0xdd).unwrap();
+        assert_eq!(&jpeg[dri + 2..dri + 6], &[0, 4, 0, 4]);
+        // 15 MCUs in intervals of 4 need three markers, numbered from zero.
+        let markers: Vec<u8> = jpeg
+            .windows(2)
+            .filter(|w| w[0] == 0xff && (0xd0..=0xd7).contains(&w[1]))
+            .map(|w| w[1])
+            .collect();
+        assert_eq!(markers, vec![0xd0, 0xd1, 0xd2]);
+    }
+
+    #[test]
+    fn arithmetic_coding() {
+        const W: i32 = 35;
+        const H: i32 = 21;
+        let data: Vec<u8> = (0..W * H * 3).map(|i| ((i * i) % 241) as u8).collect();
+        let mut options = EncoderOptions {
+            quality: Quality::Level(75),
+            subsampling: Subsampling::S420,
+            arithmetic_coding: true,
+            ..EncoderOptions::default()
+        };
+        for &(progressive, marker) in [(false, 0xc9), (true, 0xca)].iter() {
+            options.progressive = progressive;
+            let jpeg =
+                encode_to_buffer_with_options(&options, W, H, PixelFormat::Rgb, &data).unwrap();
+            assert!(find_marker(&jpeg, marker).is_some());
+            // Conditioning for both tables of both classes, and no Huffman
+            // tables.
+            let dac = find_marker(&jpeg, 0xcc).unwrap();
+            let conditioning = [0, 10, 0x00, 0x10, 0x10, 5, 0x01, 0x10, 0x11, 5];
+            assert_eq!(&jpeg[dac + 2..dac + 12], &conditioning);
+            assert!(find_marker(&jpeg, 0xc4).is_none());
+            assert_eq!(&jpeg[jpeg.len() - 2..], &[0xff, 0xd9]);
+        }
+    }
+
+    #[test]
+    fn twelve_bit() {
+        const W: u32 = 21;
+        const H: u32 = 13;
+        let data: Vec<u16> = (0..W * H).map(|i| ((i * 97) % 4096) as u16).collect();
+        let image = ImageView::new(&data[..], W, H, PixelFormat::Gray);
+        let options = EncoderOptions {
+            quality: Quality::Level(1),
+            ..EncoderOptions::default()
+        };
+        let jpeg = encode_image(&options, &image).unwrap();
+        // Extended sequential, 12 bits per sample.
+        let sof = find_marker(&jpeg, 0xc1).unwrap();
+        assert_eq!(jpeg[sof + 4], 12);
+        // A 16-bit quantization table, with entries past 255.
+        let dqt = find_marker(&jpeg, 0xdb).unwrap();
+        assert_eq!(&jpeg[dqt + 2..dqt + 5], &[0, 131, 0x10]);
+        assert_eq!(&jpeg[dqt + 5..dqt + 7], &[0x03, 0x20]);
+        assert_eq!(&jpeg[jpeg.len() - 2..], &[0xff, 0xd9]);
+    }
+
+    #[test]
+    fn lossless() {
+        const W: i32 = 17;
+        const H: i32 = 9;
+        let data: Vec<u8> = (0..W * H * 3).map(|i| ((i * 5) % 256) as u8).collect();
+        let options = EncoderOptions {
+            lossless: Some(Predictor::Gradient),
+            point_transform: 1,
+            ..EncoderOptions::default()
+        };
+        let jpeg = encode_to_buffer_with_options(&options, W, H, PixelFormat::Rgb, &data).unwrap();
+        assert!(find_marker(&jpeg, 0xc3).is_some());
+        assert!(find_marker(&jpeg, 0xdb).is_none());
+        // RGB components are flagged with an Adobe segment instead of JFIF.
+        assert!(find_marker(&jpeg, 0xe0).is_none());
+        let app14 = find_marker(&jpeg, 0xee).unwrap();
+        assert_eq!(jpeg[app14 + 15], 0);
+        // Predictor, end of spectral selection and point transform.
+        let sos = find_marker(&jpeg, 0xda).unwrap();
+        assert_eq!(&jpeg[sos + 11..sos + 14], &[4, 0, 1]);
+        assert_eq!(&jpeg[jpeg.len() - 2..], &[0xff, 0xd9]);
+
+        // 16-bit samples, where the differences of 32768 between the
+        // checkerboard's squares have a category of their own.
+        let samples: Vec<_> = (0..W * H)
+            .map(|i| Bits::<16>((((i % W + i / W) % 2) as u16) << 15))
+            .collect();
+        let options = EncoderOptions {
+            lossless: Some(Predictor::Left),
+            ..EncoderOptions::default()
+        };
+        let image = ImageView::new(&samples, W as u32, H as u32, PixelFormat::Gray);
+        let jpeg = encode_image(&options, &image).unwrap();
+        let sof = find_marker(&jpeg, 0xc3).unwrap();
+        assert_eq!(jpeg[sof + 4], 16);
+        // A single code, one bit long, for category 16.
+        let dht = find_marker(&jpeg, 0xc4).unwrap();
+        assert_eq!(jpeg[dht + 5], 1);
+        assert_eq!(jpeg[dht + 21], 16);
+    }
+
+    #[test]
+    fn cmyk() {
+        const W: i32 = 19;
+        const H: i32 = 11;
+        let data: Vec<u8> = (0..W * H * 4).map(|i| ((i * 13) % 256) as u8).collect();
+        let cases = [(false, 0, [0, 0, 0, 0]), (true, 2, [0, 1, 1, 0])];
+        for &(ycck, transform, tables) in cases.iter() {
+            let options = EncoderOptions {
+                ycck,
+                ..EncoderOptions::default()
+            };
+            let jpeg =
+                encode_to_buffer_with_options(&options, W, H, PixelFormat::Cmyk, &data).unwrap();
+            // Four components with the quantization tables of CMYK or YCCK.
+            let sof = find_marker(&jpeg, 0xc0).unwrap();
+            assert_eq!(jpeg[sof + 9], 4);
+            for c in 0..4 {
+                assert_eq!(jpeg[sof + 12 + 3 * c], tables[c]);
+            }
+            // An Adobe segment instead of JFIF, with the color transform.
+            assert!(find_marker(&jpeg, 0xe0).is_none());
+            let app14 = find_marker(&jpeg, 0xee).unwrap();
+            assert_eq!(&jpeg[app14 + 4..app14 + 9], b"Adobe");
+            assert_eq!(jpeg[app14 + 15], transform);
+            let sos = find_marker(&jpeg, 0xda).unwrap();
+            assert_eq!(jpeg[sos + 4], 4);
+        }
+
+        // Samples above the maximum of their precision are clamped before
+        // they are inverted.
+        let over: Vec<_> = data.iter().map(|&v| Bits::<8>(v as u16 * 3)).collect();
+        let clamped: Vec<_> = over.iter().map(|s| Bits::<8>(s.0.min(255))).collect();
+        for lossless in [None, Some(Predictor::Average)] {
+            let options = EncoderOptions {
+                lossless,
+                ..EncoderOptions::default()
+            };
+            let encode = |data: &[Bits<8>]| {
+                let image = ImageView::new(data, W as u32, H as u32, PixelFormat::Cmyk);
+                encode_image(&options, &image).unwrap()
+            };
+            assert!(encode(&over) == encode(&clamped));
+        }
+    }
+
+    #[test]
+    fn custom_quantization_tables() {
+        const W: i32 = 23;
+        const H: i32 = 17;
+        let data: Vec<u8> = (0..W * H * 3).map(|i| ((i * 7) % 256) as u8).collect();
+
+        // The example tables, unscaled, give the same file as quality 50.
+        let luma = DEFAULT_QT_LUMA_FROM_SPEC.map(|q| q as u16);
+        let chroma = DEFAULT_QT_CHROMA_FROM_SPEC.map(|q| q as u16);
+        let options = EncoderOptions {
+            quantization_tables: vec![
+                QuantizationTable::from_natural(&luma).unwrap(),
+                QuantizationTable::from_natural(&chroma).unwrap(),
+            ],
+            ..EncoderOptions::default()
+        };
+        let medium = encode_to_buffer(Quality::Medium, W, H, 3, &data).unwrap();
+        let jpeg = encode_to_buffer_with_options(&options, W, H, PixelFormat::Rgb, &data).unwrap();
+        assert_eq!(jpeg, medium);
+
+        let mut natural = [0u16; QT_SIZE];
+        for (i, entry) in natural.iter_mut().enumerate() {
+            *entry = i as u16 + 1;
+        }
+        let table = QuantizationTable::from_natural(&natural).unwrap();
+        assert_eq!(table.natural(), natural);
+        assert_eq!(table.zigzag()[2], 9);
+
+        // A table per component, one of them with 16-bit entries.
+        let options = EncoderOptions {
+            quantization_tables: vec![
+                table.clone(),
+                QuantizationTable::from_zigzag(&[300; QT_SIZE]).unwrap(),
+                table,
+            ],
+            component_quantization: Some([2, 0, 1, 0]),
+            ..EncoderOptions::default()
+        };
+        let jpeg = encode_to_buffer_with_options(&options, W, H, PixelFormat::Rgb, &data).unwrap();
+        let dqts: Vec<_> = (1..jpeg.len()).filter(|&i| jpeg[i - 1..=i] == [0xff, 0xdb]).collect();
+        assert_eq!(dqts.len(), 3);
+        // The row-major table is written in zigzag order.
+        let zigzag: [u8; QT_SIZE] = [
+            1, 2, 9, 17, 10, 3, 4, 11, 18, 25, 33, 26, 19, 12, 5, 6,
+            13, 20, 27, 34, 41, 49, 42, 35, 28, 21, 14, 7, 8, 15, 22, 29,
+            36, 43, 50, 57, 58, 51, 44, 37, 30, 23, 16, 24, 31, 38, 45, 52,
+            59, 60, 53, 46, 39, 32, 40, 47, 54, 61, 62, 55, 48, 56, 63, 64,
+        ];
+        assert_eq!(&jpeg[dqts[0] + 1..dqts[0] + 4], &[0, 67, 0]);
+        assert_eq!(jpeg[dqts[0] + 4..dqts[0] + 68], zigzag);
+        assert_eq!(&jpeg[dqts[1] + 1..dqts[1] + 4], &[0, 131, 0x11]);
+        // Extended sequential, since baseline only allows 8-bit tables.
+        let sof = find_marker(&jpeg, 0xc1).unwrap();
+        for (c, &slot) in [2, 0, 1].iter().enumerate() {
+            assert_eq!(jpeg[sof + 12 + 3 * c], slot);
+        }
+    }
+
+    #[test]
+    fn custom_huffman_tables() {
+        // Counts that don't match the symbols, a repeated symbol, and a code
+        // of all ones.
+        let mut bits = [0u8; 16];
+        bits[1] = 3;
+        assert!(HuffmanTable::new(bits, vec![1, 2]).is_none());
+        assert!(HuffmanTable::new(bits, vec![1, 2, 2]).is_none());
+        bits[1] = 4;
+        assert!(HuffmanTable::new(bits, vec![1, 2, 3, 4]).is_none());
+        bits[1] = 3;
+        assert!(HuffmanTable::new(bits, vec![1, 2, 3]).is_some());
+
+        // The example tables, given explicitly, give the same file as the
+        // defaults.
+        const W: i32 = 23;
+        const H: i32 = 17;
+        let data: Vec<u8> = (0..W * H * 3).map(|i| ((i * 7) % 256) as u8).collect();
+        let table = |bits, vals: &[u8]| HuffmanTable::new(bits, vals.to_vec()).unwrap();
+        let luma = (
+            table(DEFAULT_HT_LUMA_DC_LEN, &DEFAULT_HT_LUMA_DC),
+            table(DEFAULT_HT_LUMA_AC_LEN, &DEFAULT_HT_LUMA_AC),
+        );
+        let chroma = (
+            table(DEFAULT_HT_CHROMA_DC_LEN, &DEFAULT_HT_CHROMA_DC),
+            table(DEFAULT_HT_CHROMA_AC_LEN, &DEFAULT_HT_CHROMA_AC),
+        );
+        let options = EncoderOptions {
+            huffman_tables: vec![luma.clone(), chroma.clone()],
+            ..EncoderOptions::default()
+        };
+        let default = encode_to_buffer(Quality::Highest, W, H, 3, &data).unwrap();
+        let jpeg = encode_to_buffer_with_options(&options, W, H, PixelFormat::Rgb, &data).unwrap();
+        assert_eq!(jpeg, default);
+
+        // Swapped tables, in slots that baseline JPEGs don't have.
+        let options = EncoderOptions {
+            huffman_tables: vec![luma.clone(), luma, chroma.clone(), chroma],
+            component_huffman: Some([2, 0, 3, 0]),
+            ..EncoderOptions::default()
+        };
+        let jpeg = encode_to_buffer_with_options(&options, W, H, PixelFormat::Rgb, &data).unwrap();
+        assert!(find_marker(&jpeg, 0xc1).is_some());
+        let sos = find_marker(&jpeg, 0xda).unwrap();
+        assert_eq!(&jpeg[sos + 5..sos + 11], &[1, 0x22, 2, 0x00, 3, 0x33]);
+    }
+
+    #[test]
+    fn trellis_quantization() {
+        const W: i32 = 45;
+        const H: i32 = 37;
+        let data = test_image(W as u32, H as u32);
+        let encode = |trellis| {
+            let options = EncoderOptions {
+                quality: Quality::Level(85),
+                subsampling: Subsampling::S420,
+                restart_interval: 3,
+                trellis,
+                ..EncoderOptions::default()
+            };
+            encode_to_buffer_with_options(&options, W, H, PixelFormat::Rgb, &data).unwrap()
+        };
+        let plain = encode(None);
+        // When distortion is all that counts, the trellis rounds like the
+        // plain quantizer.
+        assert_eq!(encode(Some(1e6)), plain);
+        assert!(encode(Some(16.0)).len() < plain.len());
+        assert!(encode(Some(1.0)).len() < encode(Some(16.0)).len());
+    }
+
+    #[test]
+    fn size_target() {
+        const W: u32 = 45;
+        const H: u32 = 37;
+        let data = test_image(W, H);
+        let image = ImageView::new(&data, W, H, PixelFormat::Rgb);
+        let mut options = EncoderOptions {
+            subsampling: Subsampling::S420,
+            ..EncoderOptions::default()
+        };
+        let encode = |options: &EncoderOptions, quality| {
+            encode_image(&EncoderOptions { quality, ..options.clone() }, &image).unwrap()
+        };
+        let budget = encode(&options, Quality::Level(60)).len();
+
+        // The cached blocks give the same JPEG as encoding from scratch.
+        let budget_target = SizeTarget::Bytes(budget);
+        let (jpeg, quality) = encode_to_size(&options, &image, budget_target).unwrap().unwrap();
+        assert!(jpeg.len() <= budget);
+        assert!(quality.value() >= 60);
+        assert_eq!(jpeg, encode(&options, quality));
+        assert!(encode(&options, Quality::Level(quality.value() + 1)).len() > budget);
+
+        options.progressive = true;
+        let bpp = SizeTarget::BitsPerPixel((budget * 8) as f32 / (W * H) as f32);
+        let (jpeg, quality) = encode_to_size(&options, &image, bpp).unwrap().unwrap();
+        assert!(jpeg.len() <= budget);
+        assert_eq!(jpeg, encode(&options, quality));
+        assert!(encode_to_size(&options, &image, SizeTarget::Bytes(100)).unwrap().is_none());
+    }
+
+    #[test]
+    fn fidelity_target() {
+        const W: u32 = 45;
+        const H: u32 = 37;
+        let data = test_image(W, H);
+        let image = ImageView::new(&data, W, H, PixelFormat::Rgb);
+        let mut options = EncoderOptions::default();
+
+        let target = FidelityTarget::Psnr(35.0);
+        let (jpeg, quality, psnr) = encode_to_fidelity(&options, &image, target).unwrap().unwrap();
+        assert!(psnr >= 35.0);
+        let expected = encode_image(&EncoderOptions { quality, ..options.clone() }, &image);
+        assert_eq!(jpeg, expected.unwrap());
+        // Higher targets take higher qualities.
+        let target = FidelityTarget::Psnr(40.0);
+        let (_, higher, _) = encode_to_fidelity(&options, &image, target).unwrap().unwrap();
+        assert!(higher.value() > quality.value());
+        let ssim_target = FidelityTarget::Ssim(0.9);
+        let (_, _, ssim) = encode_to_fidelity(&options, &image, ssim_target).unwrap().unwrap();
+        assert!((0.9..=1.0).contains(&ssim));
+        let unreachable = FidelityTarget::Psnr(100.0);
+        assert!(encode_to_fidelity(&options, &image, unreachable).unwrap().is_none());
+
+        /* Threads round the restart interval up to the 6 MCUs of a row, and
+           the trellis then resets there too. What is reported is measured
+           on the same interval as the file, so it is what a single thread
+           reports for that interval. */
+        let threaded = EncoderOptions {
+            trellis: Some(0.5),
+            threads: Some(2),
+            restart_interval: 4,
+            ..EncoderOptions::default()
+        };
+        let (jpeg, quality, psnr) = encode_to_fidelity(&threaded, &image, target).unwrap().unwrap();
+        let dri = find_marker(&jpeg, 0xdd).unwrap();
+        assert_eq!(&jpeg[dri + 4..dri + 6], &[0, 6]);
+        let single = EncoderOptions {
+            threads: None,
+            restart_interval: 6,
+            ..threaded.clone()
+        };
+        let expected = encode_to_fidelity(&single, &image, target).unwrap().unwrap();
+        assert_eq!((jpeg, quality, psnr), expected);
+
+        // Lossless frames come back exactly.
+        options.lossless = Some(Predictor::Left);
+        let (_, _, psnr) = encode_to_fidelity(&options, &image, target).unwrap().unwrap();
+        assert_eq!(psnr, f64::INFINITY);
+    }
+
+    #[test]
+    fn fixed_point() {
+        let options = EncoderOptions {
+            fixed_point: true,
+            ..EncoderOptions::default()
+        };
+
+        // The integer DCT of a flat block is only its DC coefficient, the
+        // centered sample scaled up by 64.
+        let data = [200u8; 64];
+        let image = ImageView::new(&data, 8, 8, PixelFormat::Gray);
+        let frame = Frame::new(&image, &options);
+        let mut blocks = vec![];
+        let kernels = simd::Kernels::detect();
+        image.for_each_transformed_mcu(&frame, kernels, 0..frame.mcu_rows(), &mut |mcu| {
+            blocks.extend_from_slice(mcu)
+        });
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0][0], 64.0 * 72.0);
+        assert!(blocks[0][1..].iter().all(|&c| c == 0.0));
+
+        // Checkerboards of the extreme 12-bit values stay within range.
+        let data: Vec<u16> = (0..64 * 64)
+            .map(|i| if (i % 64 + i / 64) % 2 == 0 { 4095 } else { 0 })
+            .collect();
+        encode_image(&options, &ImageView::new(&data, 64, 64, PixelFormat::Gray)).unwrap();
+
+        /* The result is the same on every platform and with any kernels, and
+           close to that of the float pipeline. */
+        const W: i32 = 45;
+        const H: i32 = 37;
+        let data = test_image(W as u32, H as u32);
+        let encode = |fixed_point| {
+            let options = EncoderOptions {
+                quality: Quality::Level(85),
+                subsampling: Subsampling::S420,
+                fixed_point,
+                ..EncoderOptions::default()
+            };
+            encode_to_buffer_with_options(&options, W, H, PixelFormat::Rgb, &data).unwrap()
+        };
+        let (fixed, float) = (encode(true), encode(false));
+        // FNV-1a.
+        let checksum = fixed
+            .iter()
+            .fold(0x811c9dc5u32, |h, &b| (h ^ b as u32).wrapping_mul(0x01000193));
+        assert_eq!((fixed.len(), checksum), (1378, 0x977c50b1));
+        assert!(fixed.len().abs_diff(float.len()) < float.len() / 20);
+    }
+
+    #[test]
+    fn threads() {
+        // 13 MCUs per row and 10 rows.
+        const W: i32 = 203;
+        const H: i32 = 157;
+        let data = test_image(W as u32, H as u32);
+        let encode = |options: &EncoderOptions| {
+            encode_to_buffer_with_options(options, W, H, PixelFormat::Rgb, &data).unwrap()
+        };
+        let variants = [
+            EncoderOptions::default(),
+            EncoderOptions {
+                optimize_huffman: true,
+                trellis: Some(0.5),
+                restart_interval: 20,
+                ..EncoderOptions::default()
+            },
+            EncoderOptions {
+                fixed_point: true,
+                ..EncoderOptions::default()
+            },
+        ];
+        for options in variants.iter() {
+            let options = EncoderOptions {
+                subsampling: Subsampling::S420,
+                ..options.clone()
+            };
+            // Restart intervals of whole rows, the same as on one thread.
+            let rows = options.restart_interval.div_ceil(13).max(1) * 13;
+            let one = encode(&EncoderOptions {
+                restart_interval: rows,
+                ..options.clone()
+            });
+            for &threads in [2, 3, 4, 16].iter() {
+                let options = EncoderOptions {
+                    threads: Some(threads),
+                    ..options.clone()
+                };
+                assert!(encode(&options) == one);
+            }
+            // A single thread keeps the restart interval as given.
+            let single = EncoderOptions {
+                threads: Some(1),
+                ..options.clone()
+            };
+            assert!(encode(&single) == encode(&options));
+        }
+
+        // So does a single row of MCUs, which is never split.
+        let data = test_image(W as u32, 8);
+        let encode_row = |options: &EncoderOptions| {
+            encode_to_buffer_with_options(options, W, 8, PixelFormat::Rgb, &data).unwrap()
+        };
+        let options = EncoderOptions {
+            restart_interval: 5,
+            ..EncoderOptions::default()
+        };
+        let threaded = EncoderOptions {
+            threads: Some(4),
+            ..options.clone()
+        };
+        let jpeg = encode_row(&threaded);
+        let dri = find_marker(&jpeg, 0xdd).unwrap();
+        assert_eq!(&jpeg[dri + 2..dri + 6], &[0, 4, 0, 5]);
+        assert!(jpeg == encode_row(&options));
+
+        // Progressive JPEGs are coded on one thread.
+        let options = EncoderOptions {
+            progressive: true,
+            ..EncoderOptions::default()
+        };
+        let threaded = EncoderOptions {
+            threads: Some(4),
+            ..options.clone()
+        };
+        assert!(encode(&threaded) == encode(&options));
+    }
+
+    #[test]
+    fn stream_encoder() {
+        const W: u32 = 203;
+        const H: u32 = 157;
+        let data = test_image(W, H);
+        let variants = [
+            EncoderOptions {
+                subsampling: Subsampling::S420,
+                ..EncoderOptions::default()
+            },
+            EncoderOptions {
+                quality: Quality::Level(80),
+                trellis: Some(0.5),
+                restart_interval: 7,
+                ..EncoderOptions::default()
+            },
+            EncoderOptions {
+                subsampling: Subsampling::S422,
+                fixed_point: true,
+                ..EncoderOptions::default()
+            },
+            EncoderOptions {
+                restart_interval: 7,
+                threads: Some(2),
+                ..EncoderOptions::default()
+            },
+        ];
+        // The stream gives what the whole image gives, whatever the rows come
+        // in.
+        for options in variants.iter() {
+            let image = ImageView::new(&data, W, H, PixelFormat::Rgb);
+            let whole = encode_image(options, &image).unwrap();
+            let mut encoder = StreamEncoder::new(vec![], options, W, H, PixelFormat::Rgb).unwrap();
+            let row = W as usize * 3;
+            let mut top = 0;
+            for count in (0..).map(|i| i % 20) {
+                if top == H as usize {
+                    break;
+                }
+                let bottom = (top + count).min(H as usize);
+                encoder.push_rows(&data[top * row..bottom * row]).unwrap();
+                top = bottom;
+            }
+            assert!(encoder.finish().unwrap() == whole);
+        }
+
+        // Once the sink fails, the rows that follow are refused, even those
+        // that don't complete a row of MCUs.
+        let options = EncoderOptions::default();
+        let whole = encode_image(&options, &ImageView::new(&data, W, H, PixelFormat::Rgb)).unwrap();
+        let mut buffer = vec![0u8; whole.len() / 2];
+        let sink: &mut [u8] = &mut buffer;
+        let mut encoder = StreamEncoder::new(sink, &options, W, H, PixelFormat::Rgb).unwrap();
+        let row = W as usize * 3;
+        let failed = (0..H as usize).find(|&y| encoder.push_rows(&data[y * row..][..row]).is_err());
+        let next = failed.unwrap() + 1;
+        assert_eq!(next % 8, 0);
+        let is_write_zero = |result| match result {
+            Err(EncodeError::Io(e)) => e.kind() == io::ErrorKind::WriteZero,
+            _ => false,
+        };
+        assert!(is_write_zero(encoder.push_rows(&data[next * row..][..row])));
+        assert!(is_write_zero(encoder.finish().map(|_| ())));
+    }
+
+    #[test]
+    fn writer() {
+        // Takes `capacity` bytes, and records the longest write.
+        struct Sink {
+            data: Vec<u8>,
+            capacity: usize,
+            longest: usize,
+        }
+        impl Write for Sink {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.data.len() + buf.len() > self.capacity {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero, "full"));
+                }
+                self.data.extend_from_slice(buf);
+                self.longest = self.longest.max(buf.len());
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        const W: u32 = 640;
+        const H: u32 = 480;
+        let data: Vec<u8> = (0..W * H * 3).map(|i| (i.wrapping_mul(i) % 251) as u8).collect();
+        let image = ImageView::new(&data, W, H, PixelFormat::Rgb);
+        let variants = [
+            EncoderOptions::default(),
+            EncoderOptions {
+                progressive: true,
+                ..EncoderOptions::default()
+            },
+        ];
+        for options in variants.iter() {
+            // The sink gets what the buffer gets, a chunk at a time.
+            let whole = encode_image(options, &image).unwrap();
+            assert!(whole.len() > 4 * writer::CHUNK);
+            let mut sink = Sink { data: vec![], capacity: usize::MAX, longest: 0 };
+            encode_to_writer(&mut sink, options, &image).unwrap();
+            assert!(sink.data == whole);
+            assert!(sink.longest < 2 * writer::CHUNK);
+
+            // Errors from the sink come back.
+            let mut sink = Sink { data: vec![], capacity: whole.len() / 2, longest: 0 };
+            match encode_to_writer(&mut sink, options, &image) {
+                Err(EncodeError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::WriteZero),
+                _ => panic!("the error was lost"),
+            }
+        }
+    }
+
+    #[test]
+    fn encode_errors() {
+        let data = vec![0u8; 16 * 8 * 3];
+        let rgb = |w, h, data: &[u8]| encode_to_buffer(Quality::High, w, h, 3, data);
+        assert!(rgb(16, 8, &data).is_ok());
+
+        // Dimensions and components that JPEG can't store, and short data.
+        for &(w, h) in [(0, 8), (-16, 8), (16, -1), (0x10000, 1)].iter() {
+            match rgb(w, h, &data) {
+                Err(EncodeError::InvalidDimensions { width, height }) => {
+                    assert_eq!((width, height), (w as i64, h as i64))
+                }
+                _ => panic!("{}x{} was encoded", w, h),
+            }
+        }
+        assert!(matches!(
+            encode_to_buffer(Quality::High, 16, 8, 5, &data),
+            Err(EncodeError::UnsupportedComponents(5))
+        ));
+        assert!(matches!(
+            rgb(16, 9, &data),
+            Err(EncodeError::BufferLength { expected: 432, actual: 384 })
+        ));
+        let mut view = ImageView::new(&data, 16, 4, PixelFormat::Rgb);
+        view.stride = 40;
+        assert!(matches!(
+            encode_image(&EncoderOptions::default(), &view),
+            Err(EncodeError::InvalidStride { stride: 40, row_len: 48 })
+        ));
+        // Views whose ends don't fit in a usize.
+        let mut view = ImageView::new(&data, 16, 4, PixelFormat::Rgb);
+        view.x = usize::MAX;
+        assert!(matches!(
+            encode_image(&EncoderOptions::default(), &view),
+            Err(EncodeError::InvalidStride { stride: 48, row_len: usize::MAX })
+        ));
+        view.height = 1;
+        assert!(matches!(
+            encode_image(&EncoderOptions::default(), &view),
+            Err(EncodeError::BufferLength { expected: usize::MAX, actual: 384 })
+        ));
+        let mut view = ImageView::new(&data, 16, 4, PixelFormat::Rgb);
+        view.y = usize::MAX;
+        assert!(matches!(
+            encode_image(&EncoderOptions::default(), &view),
+            Err(EncodeError::BufferLength { expected: usize::MAX, actual: 384 })
+        ));
+        view.y = 0;
+        view.stride = usize::MAX / 2;
+        assert!(matches!(
+            encode_image(&EncoderOptions::default(), &view),
+            Err(EncodeError::BufferLength { expected: usize::MAX, actual: 384 })
+        ));
+
+        // Tables that components refer to but that aren't given, or that
+        // can't code 12-bit values, and point transforms of every bit.
+        assert!(QuantizationTable::from_zigzag(&[0; QT_SIZE]).is_none());
+        let image = ImageView::new(&data, 16, 8, PixelFormat::Rgb);
+        let invalid = |options: EncoderOptions| {
+            let error = encode_image(&options, &image).unwrap_err();
+            assert!(!error.to_string().is_empty());
+            error
+        };
+        let table = QuantizationTable::from_zigzag(&[1; QT_SIZE]).unwrap();
+        assert!(matches!(
+            invalid(EncoderOptions {
+                quantization_tables: vec![table],
+                component_quantization: Some([0, 1, 1, 0]),
+                ..EncoderOptions::default()
+            }),
+            EncodeError::InvalidTables(_)
+        ));
+        let table = |bits, vals: &[u8]| HuffmanTable::new(bits, vals.to_vec()).unwrap();
+        let luma = (
+            table(DEFAULT_HT_LUMA_DC_LEN, &DEFAULT_HT_LUMA_DC),
+            table(DEFAULT_HT_LUMA_AC_LEN, &DEFAULT_HT_LUMA_AC),
+        );
+        let samples = vec![0u16; 16 * 8 * 3];
+        let options = EncoderOptions {
+            huffman_tables: vec![luma],
+            ..EncoderOptions::default()
+        };
+        let image12 = ImageView::new(&samples, 16, 8, PixelFormat::Rgb);
+        assert!(encode_image(&options, &image).is_ok());
+        // Even for a 12-bit image whose zeros the tables could code.
+        assert!(matches!(encode_image(&options, &image12), Err(EncodeError::InvalidTables(_))));
+        assert!(matches!(
+            invalid(EncoderOptions {
+                lossless: Some(Predictor::Left),
+                point_transform: 8,
+                ..EncoderOptions::default()
+            }),
+            EncodeError::UnsupportedOptions(_)
+        ));
+
+        // Trellis lambdas that don't weigh errors against bits.
+        for lambda in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+            let trellis = EncoderOptions {
+                trellis: Some(lambda),
+                ..EncoderOptions::default()
+            };
+            assert!(matches!(
+                encode_image(&trellis, &image),
+                Err(EncodeError::UnsupportedOptions("trellis lambda"))
+            ));
+        }
+
+        // Samples of a precision that the kind of JPEG doesn't have.
+        let options = EncoderOptions::default();
+        let lossless = EncoderOptions {
+            lossless: Some(Predictor::Left),
+            ..EncoderOptions::default()
+        };
+        let unsupported = |result: Result<Vec<u8>, EncodeError>| {
+            matches!(result, Err(EncodeError::UnsupportedOptions("sample precision")))
+        };
+        let samples = vec![Bits::<0>(0); 16 * 8 * 3];
+        let image0 = ImageView::new(&samples, 16, 8, PixelFormat::Rgb);
+        assert!(unsupported(encode_image(&lossless, &image0)));
+        let samples = vec![Bits::<10>(0); 16 * 8 * 3];
+        let image10 = ImageView::new(&samples, 16, 8, PixelFormat::Rgb);
+        assert!(unsupported(encode_image(&options, &image10)));
+        let samples = vec![Bits::<16>(0); 16 * 8 * 3];
+        let image16 = ImageView::new(&samples, 16, 8, PixelFormat::Rgb);
+        assert!(unsupported(encode_image(&options, &image16)));
+        assert!(encode_image(&lossless, &image16).is_ok());
+
+        // Streams take whole rows, no more than the image has, and all of
+        // them.
+        let options = EncoderOptions::default();
+        let progressive = EncoderOptions {
+            progressive: true,
+            ..EncoderOptions::default()
+        };
+        assert!(matches!(
+            StreamEncoder::<_, u8>::new(vec![], &progressive, 16, 8, PixelFormat::Rgb),
+            Err(EncodeError::UnsupportedOptions(_))
+        ));
+        let mut encoder = StreamEncoder::new(vec![], &options, 16, 8, PixelFormat::Rgb).unwrap();
+        assert!(matches!(
+            encoder.push_rows(&data[..50]),
+            Err(EncodeError::BufferLength { expected: 96, actual: 50 })
+        ));
+        encoder.push_rows(&data[..48 * 3]).unwrap();
+        assert!(matches!(
+            encoder.push_rows(&data),
+            Err(EncodeError::BufferLength { expected: 240, actual: 384 })
+        ));
+        assert!(matches!(
+            encoder.finish(),
+            Err(EncodeError::BufferLength { expected: 384, actual: 144 })
+        ));
+    }
+
+    #[test]
+    fn encoder_builder() {
+        let data: Vec<u8> = (0..32 * 16 * 3).map(|i| (i * 7 % 251) as u8).collect();
+        let image = ImageView::new(&data, 32, 16, PixelFormat::Rgb);
+
+        // The builder gives the same bytes as the options it sets.
+        let encoder = Encoder::new()
+            .quality(Quality::Level(80))
+            .subsampling(Subsampling::S420)
+            .restart_interval(2)
+            .entropy_coding(EntropyCoding::Arithmetic);
+        let options = EncoderOptions {
+            quality: Quality::Level(80),
+            subsampling: Subsampling::S420,
+            restart_interval: 2,
+            arithmetic_coding: true,
+            ..EncoderOptions::default()
+        };
+        let jpeg = encoder.encode(&image).unwrap();
+        assert_eq!(jpeg, encode_image(&options, &image).unwrap());
+        let mut sink = vec![];
+        encoder.encode_to_writer(&mut sink, &image).unwrap();
+        assert_eq!(sink, jpeg);
+        let mut stream = Encoder::new().stream(vec![], 32, 16, PixelFormat::Rgb).unwrap();
+        stream.push_rows(&data).unwrap();
+        assert_eq!(stream.finish().unwrap(), Encoder::new().encode(&image).unwrap());
+
+        // Files are only created for valid settings.
+        let dest = env::temp_dir().join("tiny-jpeg-encoder-builder.jpg");
+        let _ = fs::remove_file(&dest);
+        assert!(Encoder::new().trellis(0.0).encode_to_file(&dest, &image).is_err());
+        assert!(!dest.exists());
+        encoder.encode_to_file(&dest, &image).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), jpeg);
+        fs::remove_file(&dest).unwrap();
+
+        // The rest of the settings give the same bytes as their options too.
+        let cmyk: Vec<u8> = (0..32 * 16 * 4).map(|i| (i * 5 % 251) as u8).collect();
+        let cmyk = ImageView::new(&cmyk, 32, 16, PixelFormat::Cmyk);
+        let quantization = vec![QuantizationTable::from_zigzag(&[3; QT_SIZE]).unwrap()];
+        let table = |bits, vals: &[u8]| HuffmanTable::new(bits, vals.to_vec()).unwrap();
+        let huffman = vec![(
+            table(DEFAULT_HT_LUMA_DC_LEN, &DEFAULT_HT_LUMA_DC),
+            table(DEFAULT_HT_LUMA_AC_LEN, &DEFAULT_HT_LUMA_AC),
+        )];
+        let encoder = Encoder::new()
+            .trellis(0.5)
+            .fixed_point(true)
+            .ycck(true)
+            .quantization_tables(quantization.clone(), Some([0; 4]))
+            .huffman_tables(huffman.clone(), None);
+        let options = EncoderOptions {
+            trellis: Some(0.5),
+            fixed_point: true,
+            ycck: true,
+            quantization_tables: quantization,
+            component_quantization: Some([0; 4]),
+            huffman_tables: huffman,
+            ..EncoderOptions::default()
+        };
+        assert_eq!(encoder.encode(&cmyk).unwrap(), encode_image(&options, &cmyk).unwrap());
+        let encoder = Encoder::new().lossless(Predictor::Above).point_transform(2);
+        let options = EncoderOptions {
+            lossless: Some(Predictor::Above),
+            point_transform: 2,
+            ..EncoderOptions::default()
+        };
+        assert_eq!(encoder.encode(&cmyk).unwrap(), encode_image(&options, &cmyk).unwrap());
+
+        // The density goes in the JFIF segment, and the comment, if any, in
+        // a COM segment of its own.
+        let metadata = Metadata {
+            density: Density::PerCentimeter(118, 59),
+            comment: Some(b"tiny".to_vec()),
+        };
+        let jpeg = Encoder::new().metadata(metadata).encode(&image).unwrap();
+        assert_eq!(&jpeg[13..18], &[2, 0, 118, 0, 59]);
+        assert!(jpeg.windows(8).any(|w| w == b"\xff\xfe\x00\x06tiny"));
+        let metadata = Metadata {
+            density: Density::AspectRatio(1, 1),
+            comment: None,
+        };
+        let jpeg = Encoder::new().metadata(metadata).encode(&image).unwrap();
+        assert_eq!(&jpeg[13..18], &[0, 0, 1, 0, 1]);
+        assert!(!jpeg.windows(2).any(|w| w == b"\xff\xfe"));
+        let metadata = Metadata {
+            comment: Some(vec![b'x'; 0x10000]),
+            ..Metadata::default()
+        };
+        assert!(matches!(
+            Encoder::new().metadata(metadata).encode(&image),
+            Err(EncodeError::UnsupportedOptions(_))
+        ));
+    }
+
+    #[test]
+    fn prepared_encoder() {
+        fn shared<T: Send + Sync>(_: &T) {}
+        let frame = |seed: u32| -> Vec<u8> {
+            (0..48 * 32 * 3u32).map(|i| (i.wrapping_mul(seed) % 253) as u8).collect()
+        };
+        let frames = [frame(7), frame(11), frame(13)];
+        let views: Vec<_> = frames
+            .iter()
+            .map(|data| ImageView::new(data, 48, 32, PixelFormat::Rgb))
+            .collect();
+        let encoder = Encoder::new().quality(Quality::Level(75)).subsampling(Subsampling::S420);
+        let prepared = encoder.prepare().unwrap();
+        shared(&prepared);
+
+        // Every frame comes out as it would without the tables being kept,
+        // and the buffer is allocated once for frames of the same size.
+        let mut out = vec![];
+        prepared.encode_into(&views[0], &mut out).unwrap();
+        assert_eq!(out, encoder.encode(&views[0]).unwrap());
+        out.reserve(out.len());
+        let buffer = out.as_ptr();
+        for view in views.iter() {
+            prepared.encode_into(view, &mut out).unwrap();
+            assert_eq!(out, encoder.encode(view).unwrap());
+            assert_eq!(out.as_ptr(), buffer);
+        }
+        let mut sink = vec![];
+        prepared.encode_to_writer(&mut sink, &views[1]).unwrap();
+        assert_eq!(sink, prepared.encode(&views[1]).unwrap());
+        let short = ImageView::new(&frames[0][..100], 48, 32, PixelFormat::Rgb);
+        assert!(prepared.encode_into(&short, &mut out).is_err());
+        assert_eq!(out, encoder.encode(&views[2]).unwrap());
+
+        // The trellis is kept for frames of any size, and restarts where
+        // each of them does.
+        let trellis = Encoder::from(EncoderOptions {
+            trellis: Some(0.5),
+            threads: Some(2),
+            restart_interval: 5,
+            ..EncoderOptions::default()
+        });
+        let mut narrow = views[1];
+        narrow.width = 40;
+        let prepared_trellis = trellis.prepare().unwrap();
+        for view in [views[0], narrow] {
+            assert_eq!(prepared_trellis.encode(&view).unwrap(), trellis.encode(&view).unwrap());
+        }
+
+        // Samples that can't be sent to other threads give an encoder that
+        // can be.
+        #[derive(Clone, Copy)]
+        struct Local(u8, PhantomData<MutexGuard<'static, ()>>);
+        impl Sample for Local {
+            const PRECISION: u8 = 8;
+
+            fn to_u16(self) -> u16 {
+                self.0 as u16
+            }
+        }
+        shared(&encoder.prepare::<Local>().unwrap());
+
+        // Threads share the encoder.
+        thread::scope(|scope| {
+            let prepared = &prepared;
+            let jpegs: Vec<_> = views
+                .iter()
+                .map(|view| scope.spawn(move || prepared.encode(view).unwrap()))
+                .collect();
+            for (jpeg, view) in jpegs.into_iter().zip(views.iter()) {
+                assert_eq!(jpeg.join().unwrap(), encoder.encode(view).unwrap());
+            }
+        });
+
+        // Tables are built for one sample type, and checked when they are.
+        let samples: Vec<u16> = frames[0].iter().map(|&s| s as u16 * 16).collect();
+        let image12 = ImageView::new(&samples, 48, 32, PixelFormat::Rgb);
+        let prepared12 = encoder.prepare::<u16>().unwrap();
+        assert_eq!(prepared12.encode(&image12).unwrap(), encoder.encode(&image12).unwrap());
+        let options = EncoderOptions {
+            lossless: Some(Predictor::Left),
+            point_transform: 8,
+            ..EncoderOptions::default()
+        };
+        assert!(Encoder::from(options.clone()).prepare::<u8>().is_err());
+        assert!(Encoder::from(options).prepare::<u16>().is_ok());
+    }
+
+    #[test]
+    fn simd_kernels() {
+        let mut seed = 1u32;
+        let mut next = move |range: u32| {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            (seed >> 8) % range
+        };
+        let scalar = simd::Kernels::scalar();
+
+        // Every set of kernels that this CPU runs gives the same bits as the
+        // scalar code, on random blocks and on checkerboards of the extremes.
+        for kernels in simd::Kernels::available() {
+            for round in 0..200 {
+                let precision = if round % 2 == 0 { 8 } else { 12 };
+                let max = (1 << precision) - 1;
+                let sample = |next: &mut dyn FnMut(u32) -> u32, i: usize| match round % 5 {
+                    0 => (i % 8 + i / 8) as i32 % 2 * max,
+                    _ => next(max as u32 + 1) as i32,
+                };
+
+                let mut planes = [[0f32; 256]; 4];
+                let mut fixed_planes = [[0i32; 256]; 4];
+                for c in 0..3 {
+                    for i in 0..256 {
+                        fixed_planes[c][i] = sample(&mut next, i);
+                        planes[c][i] = fixed_planes[c][i] as f32;
+                    }
+                }
+                let (mut a, mut b) = (planes, planes);
+                (scalar.rgb_to_ycbcr)(&mut a, 256, (max / 2 + 1) as f32);
+                (kernels.rgb_to_ycbcr)(&mut b, 256, (max / 2 + 1) as f32);
+                let same = |a: &[f32], b: &[f32]| {
+                    a.iter().zip(b).all(|(x, y)| x.to_bits() == y.to_bits())
+                };
+                assert!((0..4).all(|c| same(&a[c], &b[c])));
+                let (mut a, mut b) = (fixed_planes, fixed_planes);
+                (scalar.rgb_to_ycbcr_fixed)(&mut a, 256, max / 2 + 1);
+                (kernels.rgb_to_ycbcr_fixed)(&mut b, 256, max / 2 + 1);
+                assert_eq!(a, b);
+
+                let mut block = [0f32; 64];
+                let mut fixed_block = [0i32; 64];
+                for i in 0..64 {
+                    fixed_block[i] = sample(&mut next, i) - (max / 2 + 1);
+                    block[i] = fixed_block[i] as f32;
+                }
+                let (mut a, mut b) = (block, block);
+                (scalar.fdct)(&mut a);
+                (kernels.fdct)(&mut b);
+                assert!(same(&a, &b));
+                let (mut a, mut b) = (fixed_block, fixed_block);
+                (scalar.fdct_islow)(&mut a, precision);
+                (kernels.fdct_islow)(&mut b, precision);
+                assert_eq!(a, b);
+
+                let mut qt = [0f32; 64];
+                let mut divisors = [0i32; 64];
+                for i in 0..64 {
+                    divisors[i] = 8 * (1 + next(if round % 3 == 0 { 4 } else { 255 }) as i32);
+                    qt[i] = 1.0 / divisors[i] as f32 / (1 + next(3)) as f32;
+                }
+                let transformed = a.map(|c| c as f32);
+                let (mut a, mut b) = ([0i16; 64], [0i16; 64]);
+                (scalar.quantize)(&mut a, &transformed, &qt);
+                (kernels.quantize)(&mut b, &transformed, &qt);
+                assert_eq!(a, b);
+                (scalar.quantize_fixed)(&mut a, &transformed, &divisors);
+                (kernels.quantize_fixed)(&mut b, &transformed, &divisors);
+                assert_eq!(a, b);
+            }
+        }
     }
 }
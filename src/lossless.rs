@@ -0,0 +1,157 @@
+/* Lossless JPEG (SOF3) scans.
+
+   Every sample is predicted from its neighbours to the left, above and above
+   left, which the decoder has already rebuilt, and only the difference from
+   the prediction is sent, Huffman-coded the same way as DC differences.
+   Nothing is transformed or quantized, so the decoder gets back exactly the
+   samples that went in, less the low bits the point transform drops. See
+   Annex H of the JPEG spec. */
+
+use std::mem;
+
+use super::{
+    append_bits, append_dht, append_restart, append_sos_with_tables,
+    calculate_variable_length_int, flush_bits, huff_expand_table, huff_optimal_table,
+    sample_value, starts_restart_interval, Frame, ImageView, Predictor, Sample, Writer,
+};
+
+// Table H.1: the prediction from the reconstructed samples to the left (a),
+// above (b) and above left (c).
+fn predict(predictor: Predictor, a: i32, b: i32, c: i32) -> i32 {
+    match predictor {
+        Predictor::Left => a,
+        Predictor::Above => b,
+        Predictor::AboveLeft => c,
+        Predictor::Gradient => a + b - c,
+        Predictor::LeftGradient => a + ((b - c) >> 1),
+        Predictor::AboveGradient => b + ((a - c) >> 1),
+        Predictor::Average => (a + b) >> 1,
+    }
+}
+
+// Rounds a restart interval up to whole rows. The first row of every interval
+// is predicted like the first row of the image, which only works if intervals
+// start at the beginning of a row.
+pub fn restart_interval(frame: &Frame, restart_interval: usize) -> usize {
+    if restart_interval == 0 {
+        return 0;
+    }
+    let rows = restart_interval.div_ceil(frame.width).min(0xffff / frame.width);
+    rows * frame.width
+}
+
+// Writes the Huffman tables and the single scan of a lossless frame, with the
+// tables the frame gives each component. `restart_interval` must be a
+// whole number of rows.
+pub fn append_scan<T: Sample>(
+    out: &mut Writer,
+    frame: &Frame,
+    image: &ImageView<T>,
+    predictor: Predictor,
+    point_transform: u8,
+    restart_interval: usize,
+) {
+    assert!(point_transform < frame.precision);
+    let num_components = frame.num_components;
+    let (w, h) = (frame.width, frame.height);
+    let offsets = image.format.channel_offsets();
+    let tables = frame.tables;
+    // CMYK is stored inverted, as in the other kinds of JPEG.
+    let invert = frame.num_components == 4;
+    let max = ((1u32 << frame.precision) - 1) as u16;
+
+    /* Differences of every sample, pixel by pixel. The first sample of an
+       interval has no neighbours and is predicted from the middle of the
+       range, the rest of its first row from the left, and the first sample
+       of the other rows from above. */
+    let initial = 1 << (frame.precision - point_transform - 1);
+    let mut diffs = Vec::with_capacity(w * h * num_components);
+    let mut row = vec![0i32; w * num_components];
+    let mut prev_row = vec![0i32; w * num_components];
+    let mut first_row = true;
+    for y in 0..h {
+        if starts_restart_interval(y * w, restart_interval) {
+            first_row = true;
+        }
+        for x in 0..w {
+            let src_idx = image.offset(x, y);
+            for (c, &offset) in offsets.iter().enumerate().take(num_components) {
+                let i = x * num_components + c;
+                let mut value = sample_value(image.data[src_idx + offset]);
+                if invert {
+                    value = max - value;
+                }
+                let sample = (value >> point_transform) as i32;
+                let prediction = if x == 0 {
+                    if first_row {
+                        initial
+                    } else {
+                        prev_row[i]
+                    }
+                } else if first_row {
+                    row[i - num_components]
+                } else {
+                    let a = row[i - num_components];
+                    let c = prev_row[i - num_components];
+                    predict(predictor, a, prev_row[i], c)
+                };
+                row[i] = sample;
+                // Differences are taken modulo 2^16, from -32767 to 32768,
+                // which only 16-bit samples get to.
+                let diff = (sample - prediction) as i16 as i32;
+                diffs.push(if diff == -32768 { 32768 } else { diff });
+            }
+        }
+        first_row = false;
+        mem::swap(&mut row, &mut prev_row);
+    }
+
+    let category = |diff: i32| {
+        if diff == 0 {
+            0
+        } else {
+            calculate_variable_length_int(diff).1 as usize
+        }
+    };
+
+    let mut freq = [[0u32; 257]; 2];
+    for (k, &diff) in diffs.iter().enumerate() {
+        freq[tables[k % num_components]][category(diff)] += 1;
+    }
+    let mut ehuffsize = [[0u8; 257]; 2];
+    let mut ehuffcode = [[0u16; 256]; 2];
+    let num_tables = frame.num_tables();
+    for t in 0..num_tables {
+        let (bits, vals) = huff_optimal_table(&freq[t]);
+        append_dht(out, &bits, &vals, 0, t as u8);
+        huff_expand_table(&bits, &vals, &mut ehuffsize[t], &mut ehuffcode[t]);
+    }
+
+    // The predictor goes where the spectral selection would be, and the point
+    // transform in the place of the successive approximation bits. There are
+    // no AC tables.
+    let components: Vec<usize> = (0..num_components).collect();
+    let selectors = tables.map(|t| (t as u8) << 4);
+    append_sos_with_tables(out, &components, &selectors, predictor as u8, 0, point_transform);
+
+    let mut bitbuffer = 0u32;
+    let mut location = 0u32;
+    for (k, &diff) in diffs.iter().enumerate() {
+        let pixel = k / num_components;
+        if k % num_components == 0 && starts_restart_interval(pixel, restart_interval) {
+            let n = pixel / restart_interval - 1;
+            append_restart(out, &mut bitbuffer, &mut location, n);
+        }
+        let t = tables[k % num_components];
+        let cat = category(diff);
+        let (size, code) = (ehuffsize[t][cat] as u16, ehuffcode[t][cat]);
+        append_bits(out, &mut bitbuffer, &mut location, size, code);
+        // A difference of 32768 is the only one of category 16, and has no
+        // extra bits.
+        if cat > 0 && cat < 16 {
+            let (bits, num_bits) = calculate_variable_length_int(diff);
+            append_bits(out, &mut bitbuffer, &mut location, num_bits, bits);
+        }
+    }
+    flush_bits(out, &mut bitbuffer, &mut location);
+}
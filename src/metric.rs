@@ -0,0 +1,248 @@
+/* Measuring how close an encoding is to its source.
+
+   The quantized coefficients are decoded the way a baseline decoder would:
+   dequantized, inverse transformed, rounded to whole samples, upsampled as
+   libjpeg does and converted back to the channels of the input. Decoders
+   that upsample differently or use an integer IDCT give slightly different
+   pixels, but the quantization error dominates the difference. The decoded
+   channels are then compared with those of the source, by PSNR or by SSIM. */
+
+use std::f32::consts::PI;
+
+use super::{sample_value, Frame, ImageView, Sample, ZIG_ZAG};
+
+// The channels of an image, each a row-major plane of width x height samples,
+// in the order of `PixelFormat::channel_offsets`.
+pub type Planes = Vec<Vec<f32>>;
+
+// The samples of each channel of `image` that `frame` encodes.
+pub fn source_planes<T: Sample>(image: &ImageView<T>, frame: &Frame) -> Planes {
+    let offsets = image.format.channel_offsets();
+    let (w, h) = (frame.width, frame.height);
+    (0..frame.num_components)
+        .map(|c| {
+            let mut plane = Vec::with_capacity(w * h);
+            for y in 0..h {
+                for x in 0..w {
+                    plane.push(sample_value(image.data[image.offset(x, y) + offsets[c]]) as f32);
+                }
+            }
+            plane
+        })
+        .collect()
+}
+
+// What decoders get back from a lossless frame: the samples less the low
+// bits that the point transform drops. CMYK samples are dropped inverted, as
+// they are stored.
+pub fn lossless_planes(source: &Planes, frame: &Frame, point_transform: u8) -> Planes {
+    let max = (1u32 << frame.precision) - 1;
+    let invert = frame.num_components == 4;
+    source
+        .iter()
+        .map(|plane| {
+            plane
+                .iter()
+                .map(|&s| {
+                    let v = if invert { max - s as u32 } else { s as u32 };
+                    let v = (v >> point_transform) << point_transform;
+                    (if invert { max - v } else { v }) as f32
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// Inverse DCT of a block of dequantized coefficients, in natural order.
+fn idct(block: &mut [f32; 64]) {
+    // basis[x][u] is the weight of frequency u at sample x.
+    let mut basis = [[0f32; 8]; 8];
+    for (x, weights) in basis.iter_mut().enumerate() {
+        for (u, weight) in weights.iter_mut().enumerate() {
+            let scale = if u == 0 { 0.5 / 2f32.sqrt() } else { 0.5 };
+            *weight = scale * ((2 * x + 1) as f32 * u as f32 * PI / 16.0).cos();
+        }
+    }
+
+    let mut rows = [0f32; 64];
+    for v in 0..8 {
+        for x in 0..8 {
+            rows[v * 8 + x] = (0..8).map(|u| basis[x][u] * block[v * 8 + u]).sum();
+        }
+    }
+    for y in 0..8 {
+        for x in 0..8 {
+            block[y * 8 + x] = (0..8).map(|v| basis[y][v] * rows[v * 8 + x]).sum();
+        }
+    }
+}
+
+/* Decodes the quantized blocks of `frame`, MCU by MCU as `for_each_mcu` gives
+   them, with the quantization tables `qt`, in zigzag order by slot. */
+pub fn decode(frame: &Frame, qt: &[[u16; 64]], coefficients: &[[i16; 64]]) -> Planes {
+    let (w, h) = (frame.width, frame.height);
+    let (h_samp, v_samp) = (frame.h_samp, frame.v_samp);
+    let max = ((1 << frame.precision) - 1) as f32;
+    let level_shift = (1 << (frame.precision - 1)) as f32;
+    let mcu_components = frame.mcu_components();
+    let mcus_per_row = frame.mcus_per_row();
+
+    // Samples of each component at its own resolution, level shifted and
+    // clamped as decoders store them before upsampling.
+    let mcu_rows = coefficients.len() / mcu_components.len() / mcus_per_row;
+    let mut stored: Vec<Vec<f32>> = (0..frame.num_components)
+        .map(|c| {
+            let (hc, vc) = frame.sampling(c);
+            vec![0f32; mcus_per_row * 8 * hc * mcu_rows * 8 * vc]
+        })
+        .collect();
+    let mcus = coefficients.chunks(mcu_components.len());
+    for (mcu_index, mcu) in mcus.enumerate() {
+        // Blocks of each component in the MCU so far.
+        let mut seen = [0; 4];
+        for (du, &c) in mcu.iter().zip(mcu_components.iter()) {
+            let table = &qt[frame.quant_tables[c]];
+            let mut block = [0f32; 64];
+            for i in 0..64 {
+                block[i] = du[ZIG_ZAG[i]] as f32 * table[ZIG_ZAG[i]] as f32;
+            }
+            idct(&mut block);
+
+            let (hc, vc) = frame.sampling(c);
+            let stride = mcus_per_row * 8 * hc;
+            let left = ((mcu_index % mcus_per_row) * hc + seen[c] % hc) * 8;
+            let top = ((mcu_index / mcus_per_row) * vc + seen[c] / hc) * 8;
+            seen[c] += 1;
+            for y in 0..8 {
+                for x in 0..8 {
+                    let sample = (block[y * 8 + x] + level_shift).round().clamp(0.0, max);
+                    stored[c][(top + y) * stride + left + x] = sample;
+                }
+            }
+        }
+    }
+
+    // Along 2x factors, each pixel takes the chroma samples around its
+    // center, 3/4 of the nearer and 1/4 of the other, as libjpeg does. It
+    // repeats the samples along other factors.
+    let components: Vec<Vec<f32>> = (0..frame.num_components)
+        .map(|c| {
+            let (hc, vc) = frame.sampling(c);
+            let stride = mcus_per_row * 8 * hc;
+            let (fx, fy) = (h_samp / hc, v_samp / vc);
+            if fx == 1 && fy == 1 {
+                return (0..h)
+                    .flat_map(|y| stored[c][y * stride..y * stride + w].iter().cloned())
+                    .collect();
+            }
+            // Neighbouring samples and the weight of the second, past the
+            // edges of the image repeating the last one.
+            let taps = |i: usize, f: usize, len: usize| {
+                if f != 2 {
+                    return (i / f, i / f, 0.0);
+                }
+                let pos = ((i as f32 + 0.5) / 2.0 - 0.5).max(0.0);
+                let first = (pos as usize).min(len - 1);
+                (first, (first + 1).min(len - 1), pos - first as f32)
+            };
+            let (cw, ch) = (w.div_ceil(fx), h.div_ceil(fy));
+            let mut plane = Vec::with_capacity(w * h);
+            for y in 0..h {
+                let (y0, y1, wy) = taps(y, fy, ch);
+                for x in 0..w {
+                    let (x0, x1, wx) = taps(x, fx, cw);
+                    let at = |x: usize, y: usize| stored[c][y * stride + x];
+                    let above = at(x0, y0) * (1.0 - wx) + at(x1, y0) * wx;
+                    let below = at(x0, y1) * (1.0 - wx) + at(x1, y1) * wx;
+                    plane.push((above * (1.0 - wy) + below * wy).round());
+                }
+            }
+            plane
+        })
+        .collect();
+
+    // CMYK is stored inverted, and YCCK as YCbCr of C, M and Y with an
+    // inverted K.
+    let ycbcr = frame.num_components == 3 || frame.ycck;
+    let inverted = match frame.num_components {
+        4 if frame.ycck => 3..4,
+        4 => 0..4,
+        _ => 0..0,
+    };
+    let mut planes = components.clone();
+    for i in 0..w * h {
+        if ycbcr {
+            let luma = components[0][i];
+            let cb = components[1][i] - level_shift;
+            let cr = components[2][i] - level_shift;
+            let rgb = [
+                luma + 1.402 * cr,
+                luma - 0.344136 * cb - 0.714136 * cr,
+                luma + 1.772 * cb,
+            ];
+            for c in 0..3 {
+                planes[c][i] = rgb[c].round().clamp(0.0, max);
+            }
+        }
+        for c in inverted.clone() {
+            planes[c][i] = max - planes[c][i];
+        }
+    }
+    planes
+}
+
+// Peak signal-to-noise ratio over all channels, in dB. Identical planes give
+// infinity.
+pub fn psnr(a: &Planes, b: &Planes, precision: u8) -> f64 {
+    let max = ((1 << precision) - 1) as f64;
+    let mut sum = 0.0;
+    let mut count = 0;
+    for (pa, pb) in a.iter().zip(b.iter()) {
+        for (&x, &y) in pa.iter().zip(pb.iter()) {
+            let d = (x - y) as f64;
+            sum += d * d;
+        }
+        count += pa.len();
+    }
+    let mse = sum / count as f64;
+    10.0 * (max * max / mse).log10()
+}
+
+/* Structural similarity, averaged over 8x8 windows every 4 pixels, as x264
+   measures it, and over the channels. Images smaller than a window have one
+   window of their size. 1 means identical. */
+pub fn ssim(a: &Planes, b: &Planes, width: usize, height: usize, precision: u8) -> f64 {
+    let max = ((1 << precision) - 1) as f64;
+    let c1 = (0.01 * max) * (0.01 * max);
+    let c2 = (0.03 * max) * (0.03 * max);
+    let (win_w, win_h) = (width.min(8), height.min(8));
+
+    let mut total = 0.0;
+    let mut count = 0;
+    for (pa, pb) in a.iter().zip(b.iter()) {
+        for top in (0..=height - win_h).step_by(4) {
+            for left in (0..=width - win_w).step_by(4) {
+                let (mut sa, mut sb, mut saa, mut sbb, mut sab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+                for y in top..top + win_h {
+                    for x in left..left + win_w {
+                        let (va, vb) = (pa[y * width + x] as f64, pb[y * width + x] as f64);
+                        sa += va;
+                        sb += vb;
+                        saa += va * va;
+                        sbb += vb * vb;
+                        sab += va * vb;
+                    }
+                }
+                let n = (win_w * win_h) as f64;
+                let (mean_a, mean_b) = (sa / n, sb / n);
+                let var_a = saa / n - mean_a * mean_a;
+                let var_b = sbb / n - mean_b * mean_b;
+                let cov = sab / n - mean_a * mean_b;
+                total += (2.0 * mean_a * mean_b + c1) * (2.0 * cov + c2)
+                    / ((mean_a * mean_a + mean_b * mean_b + c1) * (var_a + var_b + c2));
+                count += 1;
+            }
+        }
+    }
+    total / count as f64
+}
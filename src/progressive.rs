@@ -0,0 +1,427 @@
+/* Progressive JPEG (SOF2) scans.
+
+   A progressive frame is sent as a series of scans over the quantized
+   coefficients of the whole image. Each scan covers a band of coefficients
+   (spectral selection) for one or more components, optionally leaving out the
+   low bits of the coefficients (successive approximation), which later
+   refinement scans send one bit at a time. See Annex G of the JPEG spec.
+
+   The coding below follows jcphuff.c from libjpeg. Every scan is run twice:
+   once to count symbols for its Huffman tables, and once to write it. */
+
+use super::{
+    append_bits, append_dht, append_restart, append_sos, calculate_variable_length_int,
+    flush_bits, huff_expand_table, huff_optimal_table, starts_restart_interval, Frame, Writer,
+};
+
+// Correction bits buffered before an EOB run is forced out, as in libjpeg.
+const MAX_CORR_BITS: usize = 1000;
+
+// Longest EOB run an EOBn symbol can express.
+const MAX_EOBRUN: u32 = 0x7fff;
+
+// A scan over coefficients `ss` to `se` (in zigzag order) of `components`.
+// `al` is the number of low bits left out; `ah` is the `al` of the previous
+// scan over the same coefficients, or 0 if this is the first.
+pub struct Scan {
+    pub components: Vec<usize>,
+    pub ss: u8,
+    pub se: u8,
+    pub ah: u8,
+    pub al: u8,
+}
+
+impl Scan {
+    fn new(components: Vec<usize>, ss: u8, se: u8, ah: u8, al: u8) -> Scan {
+        Scan {
+            components,
+            ss,
+            se,
+            ah,
+            al,
+        }
+    }
+
+    fn is_dc(&self) -> bool {
+        self.ss == 0
+    }
+
+    fn is_refinement(&self) -> bool {
+        self.ah != 0
+    }
+}
+
+// The scan script of libjpeg's jpeg_simple_progression. YCbCr frames get a
+// few early luma scans and coarse chroma; other frames treat every component
+// alike.
+pub fn default_scans(num_components: usize, ycbcr: bool) -> Vec<Scan> {
+    let all: Vec<usize> = (0..num_components).collect();
+    let mut scans = vec![Scan::new(all.clone(), 0, 0, 0, 1)];
+    if ycbcr {
+        scans.push(Scan::new(vec![0], 1, 5, 0, 2));
+        scans.push(Scan::new(vec![2], 1, 63, 0, 1));
+        scans.push(Scan::new(vec![1], 1, 63, 0, 1));
+        scans.push(Scan::new(vec![0], 6, 63, 0, 2));
+        scans.push(Scan::new(vec![0], 1, 63, 2, 1));
+        scans.push(Scan::new(all, 0, 0, 1, 0));
+        scans.push(Scan::new(vec![2], 1, 63, 1, 0));
+        scans.push(Scan::new(vec![1], 1, 63, 1, 0));
+        scans.push(Scan::new(vec![0], 1, 63, 1, 0));
+    } else {
+        for &(ss, se, ah, al) in [(1, 5, 0, 2), (6, 63, 0, 2), (1, 63, 2, 1)].iter() {
+            for c in 0..num_components {
+                scans.push(Scan::new(vec![c], ss, se, ah, al));
+            }
+        }
+        scans.push(Scan::new(all, 0, 0, 1, 0));
+        for c in 0..num_components {
+            scans.push(Scan::new(vec![c], 1, 63, 1, 0));
+        }
+    }
+    scans
+}
+
+// Codes the blocks of a scan, one at a time. The Huffman coder below and the
+// arithmetic coder both implement it.
+pub trait BlockCoder {
+    fn dc_first(&mut self, du: &[i16; 64], c: usize, al: u8);
+    fn dc_refine(&mut self, du: &[i16; 64], al: u8);
+    fn ac_first(&mut self, du: &[i16; 64], c: usize, scan: &Scan);
+    fn ac_refine(&mut self, du: &[i16; 64], c: usize, scan: &Scan);
+    // Ends restart interval `n` of a scan that includes component `c`.
+    // Nothing carries over into the next interval.
+    fn restart(&mut self, c: usize, n: usize);
+    // Ends a scan that includes component `c`.
+    fn finish(&mut self, c: usize);
+}
+
+// Receives the output of a scan. Symbols are Huffman-coded with table `table`
+// of the class the scan uses (DC or AC).
+trait ScanSink {
+    fn symbol(&mut self, table: usize, symbol: u8);
+    fn bits(&mut self, bits: u16, num_bits: u16);
+    // Ends restart interval `n`.
+    fn restart(&mut self, n: usize);
+}
+
+// Only counts symbols.
+struct SymbolCounter {
+    freq: [[u32; 257]; 2],
+}
+
+impl ScanSink for SymbolCounter {
+    fn symbol(&mut self, table: usize, symbol: u8) {
+        self.freq[table][symbol as usize] += 1;
+    }
+
+    fn bits(&mut self, _bits: u16, _num_bits: u16) {}
+
+    fn restart(&mut self, _n: usize) {}
+}
+
+struct ScanWriter<'a, 'w> {
+    out: &'a mut Writer<'w>,
+    ehuffsize: [[u8; 257]; 2],
+    ehuffcode: [[u16; 256]; 2],
+    bitbuffer: u32,
+    location: u32,
+}
+
+impl<'a, 'w> ScanSink for ScanWriter<'a, 'w> {
+    fn symbol(&mut self, table: usize, symbol: u8) {
+        let size = self.ehuffsize[table][symbol as usize];
+        debug_assert!(size != 0);
+        let code = self.ehuffcode[table][symbol as usize];
+        append_bits(
+            self.out,
+            &mut self.bitbuffer,
+            &mut self.location,
+            size as u16,
+            code,
+        );
+    }
+
+    fn bits(&mut self, bits: u16, num_bits: u16) {
+        append_bits(
+            self.out,
+            &mut self.bitbuffer,
+            &mut self.location,
+            num_bits,
+            bits,
+        );
+    }
+
+    fn restart(&mut self, n: usize) {
+        append_restart(self.out, &mut self.bitbuffer, &mut self.location, n);
+    }
+}
+
+// Coding state of one scan.
+struct ScanCoder<'s, S: 's> {
+    sink: &'s mut S,
+    // Table used by each component.
+    tables: [usize; 4],
+    // Last DC value of each component.
+    pred: [i32; 4],
+    // Number of blocks in the pending EOB run.
+    eobrun: u32,
+    // Correction bits of refinement scans, waiting for the symbols they
+    // follow. The first `be` belong to the pending EOB run.
+    corr_bits: Vec<u8>,
+    be: usize,
+}
+
+impl<'s, S: ScanSink> ScanCoder<'s, S> {
+    fn new(sink: &'s mut S, tables: [usize; 4]) -> ScanCoder<'s, S> {
+        ScanCoder {
+            sink,
+            tables,
+            pred: [0; 4],
+            eobrun: 0,
+            corr_bits: vec![],
+            be: 0,
+        }
+    }
+
+    fn emit_corr_bits(&mut self, count: usize) {
+        for b in self.corr_bits.drain(..count) {
+            self.sink.bits(b as u16, 1);
+        }
+    }
+
+    // Writes the pending EOB run and the correction bits that go with it.
+    fn emit_eobrun(&mut self, table: usize) {
+        if self.eobrun == 0 {
+            return;
+        }
+        let (_, num_bits) = calculate_variable_length_int(self.eobrun as i32);
+        let num_bits = num_bits - 1;
+        self.sink.symbol(table, (num_bits << 4) as u8);
+        if num_bits > 0 {
+            let bits = self.eobrun as u16 & ((1 << num_bits) - 1);
+            self.sink.bits(bits, num_bits);
+        }
+        self.eobrun = 0;
+        let be = self.be;
+        self.emit_corr_bits(be);
+        self.be = 0;
+    }
+}
+
+impl<'s, S: ScanSink> BlockCoder for ScanCoder<'s, S> {
+    fn dc_first(&mut self, du: &[i16; 64], c: usize, al: u8) {
+        let table = self.tables[c];
+        let val = (du[0] as i32) >> al;
+        let diff = val - self.pred[c];
+        self.pred[c] = val;
+        if diff != 0 {
+            let (bits, num_bits) = calculate_variable_length_int(diff);
+            self.sink.symbol(table, num_bits as u8);
+            self.sink.bits(bits, num_bits);
+        } else {
+            self.sink.symbol(table, 0);
+        }
+    }
+
+    fn dc_refine(&mut self, du: &[i16; 64], al: u8) {
+        let bit = ((du[0] as i32) >> al) & 1;
+        self.sink.bits(bit as u16, 1);
+    }
+
+    fn ac_first(&mut self, du: &[i16; 64], c: usize, scan: &Scan) {
+        let table = self.tables[c];
+        let mut run = 0;
+        for &coef in du[scan.ss as usize..scan.se as usize + 1].iter() {
+            // Drop the low bits of the magnitude, so values round towards
+            // zero.
+            let coef = coef as i32;
+            let val = if coef < 0 {
+                -(-coef >> scan.al)
+            } else {
+                coef >> scan.al
+            };
+            if val == 0 {
+                run += 1;
+                continue;
+            }
+            self.emit_eobrun(table);
+            while run > 15 {
+                self.sink.symbol(table, 0xf0);
+                run -= 16;
+            }
+            let (bits, num_bits) = calculate_variable_length_int(val);
+            self.sink.symbol(table, ((run << 4) | num_bits) as u8);
+            self.sink.bits(bits, num_bits);
+            run = 0;
+        }
+        if run > 0 {
+            self.eobrun += 1;
+            if self.eobrun == MAX_EOBRUN {
+                self.emit_eobrun(table);
+            }
+        }
+    }
+
+    fn ac_refine(&mut self, du: &[i16; 64], c: usize, scan: &Scan) {
+        let table = self.tables[c];
+        let (ss, se) = (scan.ss as usize, scan.se as usize);
+
+        // Magnitudes at this scan's precision, and the position of the last
+        // coefficient that becomes nonzero in this scan.
+        let mut absvalues = [0u16; 64];
+        let mut eob = 0;
+        for k in ss..se + 1 {
+            absvalues[k] = (du[k] as i32).unsigned_abs() as u16 >> scan.al;
+            if absvalues[k] == 1 {
+                eob = k;
+            }
+        }
+
+        let mut run = 0;
+        for k in ss..se + 1 {
+            let val = absvalues[k];
+            if val == 0 {
+                run += 1;
+                continue;
+            }
+            // Zero runs only need ZRL symbols if a newly nonzero coefficient
+            // follows; otherwise they are part of the EOB.
+            while run > 15 && k <= eob {
+                self.emit_eobrun(table);
+                self.sink.symbol(table, 0xf0);
+                run -= 16;
+                let count = self.corr_bits.len();
+                self.emit_corr_bits(count);
+            }
+            if val > 1 {
+                // Already nonzero: send the next bit after the next symbol.
+                self.corr_bits.push((val & 1) as u8);
+                continue;
+            }
+            // Newly nonzero: the run, a sign bit and the pending correction
+            // bits.
+            self.emit_eobrun(table);
+            self.sink.symbol(table, ((run << 4) | 1) as u8);
+            self.sink.bits(if du[k] < 0 { 0 } else { 1 }, 1);
+            let count = self.corr_bits.len();
+            self.emit_corr_bits(count);
+            run = 0;
+        }
+
+        if run > 0 || self.corr_bits.len() > self.be {
+            self.eobrun += 1;
+            self.be = self.corr_bits.len();
+            if self.eobrun == MAX_EOBRUN || self.be > MAX_CORR_BITS - 64 + 1 {
+                self.emit_eobrun(table);
+            }
+        }
+    }
+
+    fn restart(&mut self, c: usize, n: usize) {
+        let table = self.tables[c];
+        self.emit_eobrun(table);
+        self.sink.restart(n);
+        self.pred = [0; 4];
+    }
+
+    // Writes the EOB run left at the end of an AC scan.
+    fn finish(&mut self, c: usize) {
+        let table = self.tables[c];
+        self.emit_eobrun(table);
+    }
+}
+
+// Codes every block of `scan`. An interleaved scan goes MCU by MCU, and a scan
+// of one component goes over that component's blocks in raster order, each
+// block being an MCU of its own.
+pub fn code_scan<B: BlockCoder>(
+    coder: &mut B,
+    frame: &Frame,
+    coefficients: &[[i16; 64]],
+    scan: &Scan,
+    restart_interval: usize,
+) {
+    let restart = |coder: &mut B, mcu_index: usize, c: usize| {
+        if starts_restart_interval(mcu_index, restart_interval) {
+            coder.restart(c, mcu_index / restart_interval - 1);
+        }
+    };
+    let code_block = |coder: &mut B, du: &[i16; 64], c: usize| {
+        match (scan.is_dc(), scan.is_refinement()) {
+            (true, false) => coder.dc_first(du, c, scan.al),
+            (true, true) => coder.dc_refine(du, scan.al),
+            (false, false) => coder.ac_first(du, c, scan),
+            (false, true) => coder.ac_refine(du, c, scan),
+        }
+    };
+
+    if scan.components.len() > 1 {
+        let mcu_components = frame.mcu_components();
+        for (i, mcu) in coefficients.chunks(mcu_components.len()).enumerate() {
+            restart(coder, i, scan.components[0]);
+            for (du, &c) in mcu.iter().zip(mcu_components.iter()) {
+                if scan.components.contains(&c) {
+                    code_block(coder, du, c);
+                }
+            }
+        }
+    } else {
+        let c = scan.components[0];
+        let (blocks_w, blocks_h) = frame.component_blocks(c);
+        for by in 0..blocks_h {
+            for bx in 0..blocks_w {
+                restart(coder, by * blocks_w + bx, c);
+                code_block(coder, &coefficients[frame.block_index(c, bx, by)], c);
+            }
+        }
+    }
+    coder.finish(scan.components[0]);
+}
+
+// Writes the scans of a progressive frame, each with its own Huffman tables.
+pub fn append_scans(
+    out: &mut Writer,
+    frame: &Frame,
+    coefficients: &[[i16; 64]],
+    restart_interval: usize,
+) {
+    let tables = frame.tables;
+    for scan in default_scans(frame.num_components, frame.num_components == 3).iter() {
+        let mut writer = ScanWriter {
+            out,
+            ehuffsize: [[0; 257]; 2],
+            ehuffcode: [[0; 256]; 2],
+            bitbuffer: 0,
+            location: 0,
+        };
+
+        // DC refinement scans are raw bits, and need no tables.
+        if !(scan.is_dc() && scan.is_refinement()) {
+            let mut counter = SymbolCounter {
+                freq: [[0; 257]; 2],
+            };
+            let mut coder = ScanCoder::new(&mut counter, tables);
+            code_scan(&mut coder, frame, coefficients, scan, restart_interval);
+            let class = if scan.is_dc() { 0 } else { 1 };
+            for t in 0..2 {
+                if counter.freq[t].iter().all(|&f| f == 0) {
+                    continue;
+                }
+                let (bits, vals) = huff_optimal_table(&counter.freq[t]);
+                append_dht(writer.out, &bits, &vals, class, t as u8);
+                huff_expand_table(
+                    &bits,
+                    &vals,
+                    &mut writer.ehuffsize[t],
+                    &mut writer.ehuffcode[t],
+                );
+            }
+        }
+
+        let ah_al = (scan.ah << 4) | scan.al;
+        append_sos(writer.out, frame, &scan.components, scan.ss, scan.se, ah_al);
+        let mut coder = ScanCoder::new(&mut writer, tables);
+        code_scan(&mut coder, frame, coefficients, scan, restart_interval);
+        flush_bits(writer.out, &mut writer.bitbuffer, &mut writer.location);
+    }
+}
@@ -0,0 +1,879 @@
+/* SIMD kernels for the inner loops of the lossy pipelines: color conversion,
+   the DCT and quantization.
+
+   Each kernel is written once, over eight lanes of f32 or i32, and built for
+   AVX2 and SSE2 on x86_64 and for NEON on aarch64. The lanes hold eight rows
+   or columns of a block, or eight pixels, and go through the same operations
+   in the same order as the scalar code, without fused multiply-adds. So every
+   kernel gives the same bits as the scalar one it replaces, in the float
+   pipeline as well as in the fixed-point one. The widest instruction set that
+   the CPU supports is picked at run time, and other targets keep the scalar
+   code. */
+
+use std::sync::OnceLock;
+
+use super::{fdct, quantize_du, rgb_to_ycbcr, ZIG_ZAG};
+use fixed_point;
+use fixed_point::{CONST_BITS, FIX_0_11400, FIX_0_16874, FIX_0_29900, FIX_0_33126,
+                  FIX_0_41869, FIX_0_50000, FIX_0_58700, FIX_0_08131, FIX_0_298631336,
+                  FIX_0_390180644, FIX_0_541196100, FIX_0_765366865, FIX_0_899976223,
+                  FIX_1_175875602, FIX_1_501321110, FIX_1_847759065, FIX_1_961570560,
+                  FIX_2_053119869, FIX_2_562915447, FIX_3_072711336, ONE_HALF, SCALE_BITS};
+
+// The kernels that an encoding runs, one set per instruction set.
+#[derive(Clone, Copy)]
+pub struct Kernels {
+    // The float AA&N DCT, `fdct`.
+    pub fdct: fn(&mut [f32; 64]),
+    // libjpeg's integer DCT, `fixed_point::fdct_islow`, for samples of the
+    // given precision.
+    pub fdct_islow: fn(&mut [i32; 64], u8),
+    // RGB to YCbCr in place in the first planes of an MCU, over the given
+    // number of pixels, for each pipeline.
+    pub rgb_to_ycbcr: fn(&mut [[f32; 256]; 4], usize, f32),
+    pub rgb_to_ycbcr_fixed: fn(&mut [[i32; 256]; 4], usize, i32),
+    // Quantization of a transformed block into zigzag order, for each
+    // pipeline.
+    pub quantize: fn(&mut [i16; 64], &[f32; 64], &[f32; 64]),
+    pub quantize_fixed: fn(&mut [i16; 64], &[f32; 64], &[i32; 64]),
+}
+
+impl Kernels {
+    pub fn scalar() -> Kernels {
+        Kernels {
+            fdct,
+            fdct_islow: fixed_point::fdct_islow,
+            rgb_to_ycbcr,
+            rgb_to_ycbcr_fixed: fixed_point::rgb_to_ycbcr,
+            quantize: quantize_du,
+            quantize_fixed: fixed_point::quantize,
+        }
+    }
+
+    // The kernels that this CPU can run, from the scalar ones to the widest.
+    #[allow(unused_mut)]
+    pub fn available() -> Vec<Kernels> {
+        let mut kernels = vec![Kernels::scalar()];
+        #[cfg(target_arch = "x86_64")]
+        {
+            // SSE2 is part of x86_64.
+            kernels.push(sse2::KERNELS);
+            if is_x86_feature_detected!("avx2") {
+                kernels.push(avx2::KERNELS);
+            }
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            if ::std::arch::is_aarch64_feature_detected!("neon") {
+                kernels.push(neon::KERNELS);
+            }
+        }
+        kernels
+    }
+
+    // The widest kernels that this CPU can run, detected on the first call.
+    pub fn detect() -> Kernels {
+        static DETECTED: OnceLock<Kernels> = OnceLock::new();
+        *DETECTED.get_or_init(|| *Kernels::available().last().unwrap())
+    }
+}
+
+/* Eight lanes of f32, and of i32 in `I`. The implementations call the
+   intrinsics of one instruction set, so the kernels that use them must only
+   run once the CPU is known to support it. Every method is inlined into the
+   kernels, which are compiled with the instruction set enabled. */
+trait F32x8: Copy {
+    type I: I32x8;
+    unsafe fn splat(x: f32) -> Self;
+    // Loads and stores the first eight values of the slice.
+    unsafe fn load(src: &[f32]) -> Self;
+    unsafe fn store(self, dst: &mut [f32]);
+    unsafe fn add(self, other: Self) -> Self;
+    unsafe fn sub(self, other: Self) -> Self;
+    unsafe fn mul(self, other: Self) -> Self;
+    unsafe fn div(self, other: Self) -> Self;
+    // Rounds toward negative infinity, for values below 2^31.
+    unsafe fn floor(self) -> Self;
+    // `self` with the sign of `sign`.
+    unsafe fn copysign(self, sign: Self) -> Self;
+    // Converts to integers, rounding toward zero.
+    unsafe fn trunc(self) -> Self::I;
+    unsafe fn from_i32(x: Self::I) -> Self;
+    // Turns eight vectors of eight rows into eight vectors of eight columns.
+    unsafe fn transpose(rows: &mut [Self; 8]);
+}
+
+trait I32x8: Copy {
+    unsafe fn splat(x: i32) -> Self;
+    unsafe fn load(src: &[i32]) -> Self;
+    unsafe fn store(self, dst: &mut [i32]);
+    unsafe fn add(self, other: Self) -> Self;
+    unsafe fn sub(self, other: Self) -> Self;
+    // The low 32 bits of the products.
+    unsafe fn mul(self, other: Self) -> Self;
+    unsafe fn shl(self, n: i32) -> Self;
+    // Arithmetic shift right.
+    unsafe fn sra(self, n: i32) -> Self;
+    unsafe fn transpose(rows: &mut [Self; 8]);
+}
+
+/* The kernels, for any set of lanes. */
+
+#[inline(always)]
+unsafe fn load_block<V: F32x8>(data: &[f32; 64]) -> [V; 8] {
+    let mut rows = [V::splat(0.0); 8];
+    for i in 0..8 {
+        rows[i] = V::load(&data[i * 8..]);
+    }
+    rows
+}
+
+// One pass of `fdct` over eight rows or columns at once, the k-th vector
+// holding the k-th sample of each.
+#[inline(always)]
+#[allow(clippy::approx_constant, clippy::excessive_precision)]
+unsafe fn aan_pass<V: F32x8>(d: &mut [V; 8]) {
+    let tmp0 = d[0].add(d[7]);
+    let tmp7 = d[0].sub(d[7]);
+    let tmp1 = d[1].add(d[6]);
+    let tmp6 = d[1].sub(d[6]);
+    let tmp2 = d[2].add(d[5]);
+    let tmp5 = d[2].sub(d[5]);
+    let tmp3 = d[3].add(d[4]);
+    let tmp4 = d[3].sub(d[4]);
+
+    // Even part
+    let tmp10 = tmp0.add(tmp3);
+    let tmp13 = tmp0.sub(tmp3);
+    let tmp11 = tmp1.add(tmp2);
+    let tmp12 = tmp1.sub(tmp2);
+
+    d[0] = tmp10.add(tmp11);
+    d[4] = tmp10.sub(tmp11);
+
+    let z1 = tmp12.add(tmp13).mul(V::splat(0.707106781));
+    d[2] = tmp13.add(z1);
+    d[6] = tmp13.sub(z1);
+
+    // Odd part
+    let tmp10 = tmp4.add(tmp5);
+    let tmp11 = tmp5.add(tmp6);
+    let tmp12 = tmp6.add(tmp7);
+
+    let z5 = tmp10.sub(tmp12).mul(V::splat(0.382683433));
+    let z2 = V::splat(0.541196100).mul(tmp10).add(z5);
+    let z4 = V::splat(1.306562965).mul(tmp12).add(z5);
+    let z3 = tmp11.mul(V::splat(0.707106781));
+
+    let z11 = tmp7.add(z3);
+    let z13 = tmp7.sub(z3);
+
+    d[5] = z13.add(z2);
+    d[3] = z13.sub(z2);
+    d[1] = z11.add(z4);
+    d[7] = z11.sub(z4);
+}
+
+// `fdct`: the rows are transposed so that the first pass works on all of
+// them at once, and back for the columns.
+#[inline(always)]
+unsafe fn fdct_lanes<V: F32x8>(data: &mut [f32; 64]) {
+    let mut d = load_block::<V>(data);
+    V::transpose(&mut d);
+    aan_pass(&mut d);
+    V::transpose(&mut d);
+    aan_pass(&mut d);
+    for i in 0..8 {
+        d[i].store(&mut data[i * 8..]);
+    }
+}
+
+#[inline(always)]
+unsafe fn descale<V: I32x8>(x: V, n: i32) -> V {
+    x.add(V::splat(1 << (n - 1))).sra(n)
+}
+
+// One pass of `fixed_point::fdct_islow`, like `aan_pass`.
+#[inline(always)]
+unsafe fn islow_pass<V: I32x8>(d: &mut [V; 8], pass1_bits: i32, first: bool) {
+    let fix = |c: i64| V::splat(c as i32);
+
+    let tmp0 = d[0].add(d[7]);
+    let tmp7 = d[0].sub(d[7]);
+    let tmp1 = d[1].add(d[6]);
+    let tmp6 = d[1].sub(d[6]);
+    let tmp2 = d[2].add(d[5]);
+    let tmp5 = d[2].sub(d[5]);
+    let tmp3 = d[3].add(d[4]);
+    let tmp4 = d[3].sub(d[4]);
+
+    // Even part
+    let tmp10 = tmp0.add(tmp3);
+    let tmp13 = tmp0.sub(tmp3);
+    let tmp11 = tmp1.add(tmp2);
+    let tmp12 = tmp1.sub(tmp2);
+
+    let bits = if first {
+        d[0] = tmp10.add(tmp11).shl(pass1_bits);
+        d[4] = tmp10.sub(tmp11).shl(pass1_bits);
+        CONST_BITS as i32 - pass1_bits
+    } else {
+        d[0] = descale(tmp10.add(tmp11), pass1_bits);
+        d[4] = descale(tmp10.sub(tmp11), pass1_bits);
+        CONST_BITS as i32 + pass1_bits
+    };
+
+    let z1 = tmp12.add(tmp13).mul(fix(FIX_0_541196100));
+    d[2] = descale(z1.add(tmp13.mul(fix(FIX_0_765366865))), bits);
+    d[6] = descale(z1.sub(tmp12.mul(fix(FIX_1_847759065))), bits);
+
+    // Odd part
+    let z1 = tmp4.add(tmp7);
+    let z2 = tmp5.add(tmp6);
+    let z3 = tmp4.add(tmp6);
+    let z4 = tmp5.add(tmp7);
+    let z5 = z3.add(z4).mul(fix(FIX_1_175875602));
+
+    let tmp4 = tmp4.mul(fix(FIX_0_298631336));
+    let tmp5 = tmp5.mul(fix(FIX_2_053119869));
+    let tmp6 = tmp6.mul(fix(FIX_3_072711336));
+    let tmp7 = tmp7.mul(fix(FIX_1_501321110));
+    let z1 = z1.mul(fix(-FIX_0_899976223));
+    let z2 = z2.mul(fix(-FIX_2_562915447));
+    let z3 = z3.mul(fix(-FIX_1_961570560)).add(z5);
+    let z4 = z4.mul(fix(-FIX_0_390180644)).add(z5);
+
+    d[7] = descale(tmp4.add(z1).add(z3), bits);
+    d[5] = descale(tmp5.add(z2).add(z4), bits);
+    d[3] = descale(tmp6.add(z2).add(z3), bits);
+    d[1] = descale(tmp7.add(z1).add(z4), bits);
+}
+
+/* `fixed_point::fdct_islow`. The intermediates of 8-bit samples fit in 32
+   bits, as they do in libjpeg on 32-bit platforms. Those of 12-bit samples
+   don't, and take the scalar 64-bit code. */
+#[inline(always)]
+unsafe fn fdct_islow_lanes<V: F32x8>(data: &mut [i32; 64], precision: u8) {
+    if precision > 8 {
+        fixed_point::fdct_islow(data, precision);
+        return;
+    }
+    let mut d = [V::I::splat(0); 8];
+    for i in 0..8 {
+        d[i] = V::I::load(&data[i * 8..]);
+    }
+    V::I::transpose(&mut d);
+    islow_pass(&mut d, 2, true);
+    V::I::transpose(&mut d);
+    islow_pass(&mut d, 2, false);
+    for i in 0..8 {
+        d[i].store(&mut data[i * 8..]);
+    }
+}
+
+// `rgb_to_ycbcr`, eight pixels at a time. MCUs are multiples of 8 pixels.
+#[inline(always)]
+unsafe fn rgb_to_ycbcr_lanes<V: F32x8>(planes: &mut [[f32; 256]; 4], len: usize,
+                                       level_shift: f32) {
+    for i in (0..len).step_by(8) {
+        let r = V::load(&planes[0][i..]);
+        let g = V::load(&planes[1][i..]);
+        let b = V::load(&planes[2][i..]);
+
+        let luma = V::splat(0.299).mul(r).add(V::splat(0.587).mul(g))
+            .add(V::splat(0.114).mul(b)).sub(V::splat(level_shift));
+        let cb = V::splat(-0.1687).mul(r).sub(V::splat(0.3313).mul(g))
+            .add(V::splat(0.5).mul(b));
+        let cr = V::splat(0.5).mul(r).sub(V::splat(0.4187).mul(g))
+            .sub(V::splat(0.0813).mul(b));
+
+        luma.store(&mut planes[0][i..]);
+        cb.store(&mut planes[1][i..]);
+        cr.store(&mut planes[2][i..]);
+    }
+}
+
+// `fixed_point::rgb_to_ycbcr`, eight pixels at a time.
+#[inline(always)]
+unsafe fn rgb_to_ycbcr_fixed_lanes<V: F32x8>(planes: &mut [[i32; 256]; 4], len: usize,
+                                             center: i32) {
+    let fix = |c: i32| V::I::splat(c);
+    let chroma_offset = fix((center << SCALE_BITS) + ONE_HALF - 1);
+    for i in (0..len).step_by(8) {
+        let r = V::I::load(&planes[0][i..]);
+        let g = V::I::load(&planes[1][i..]);
+        let b = V::I::load(&planes[2][i..]);
+
+        let luma = r.mul(fix(FIX_0_29900)).add(g.mul(fix(FIX_0_58700)))
+            .add(b.mul(fix(FIX_0_11400))).add(fix(ONE_HALF));
+        let cb = r.mul(fix(-FIX_0_16874)).sub(g.mul(fix(FIX_0_33126)))
+            .add(b.mul(fix(FIX_0_50000))).add(chroma_offset);
+        let cr = r.mul(fix(FIX_0_50000)).sub(g.mul(fix(FIX_0_41869)))
+            .sub(b.mul(fix(FIX_0_08131))).add(chroma_offset);
+
+        luma.sra(SCALE_BITS).store(&mut planes[0][i..]);
+        cb.sra(SCALE_BITS).store(&mut planes[1][i..]);
+        cr.sra(SCALE_BITS).store(&mut planes[2][i..]);
+    }
+}
+
+// `quantize_du`: the rounding in lanes, then the reordering.
+#[inline(always)]
+unsafe fn quantize_lanes<V: F32x8>(du: &mut [i16; 64], dct_mcu: &[f32; 64], qt: &[f32; 64]) {
+    let mut values = [0f32; 64];
+    for i in (0..64).step_by(8) {
+        let fval = V::load(&dct_mcu[i..]).mul(V::load(&qt[i..]));
+        let fval = fval.add(V::splat(1024.0)).add(V::splat(0.5)).floor();
+        fval.sub(V::splat(1024.0)).store(&mut values[i..]);
+    }
+    for i in 0..64 {
+        du[ZIG_ZAG[i]] = values[i] as i16;
+    }
+}
+
+/* `fixed_point::quantize`, dividing in f32. The dividends, the coefficients
+   plus or minus half the divisor, are integers below 2^21, and so are the
+   divisors. A quotient that isn't an integer is then further than its
+   rounding error from the next integer, and truncating it gives the integer
+   quotient. */
+#[inline(always)]
+unsafe fn quantize_fixed_lanes<V: F32x8>(du: &mut [i16; 64], dct_mcu: &[f32; 64],
+                                         divisors: &[i32; 64]) {
+    let mut values = [0i32; 64];
+    for i in (0..64).step_by(8) {
+        let coef = V::load(&dct_mcu[i..]);
+        let divisor = V::I::load(&divisors[i..]);
+        let half = V::from_i32(divisor.sra(1)).copysign(coef);
+        let value = coef.add(half).div(V::from_i32(divisor)).trunc();
+        value.store(&mut values[i..]);
+    }
+    for i in 0..64 {
+        du[ZIG_ZAG[i]] = values[i] as i16;
+    }
+}
+
+/* The kernels for one instruction set, compiled with it enabled. The
+   resulting `KERNELS` must only be used on CPUs that support it. */
+macro_rules! kernels {
+    ($feature:tt, $lanes:ty) => {
+        #[target_feature(enable = $feature)]
+        unsafe fn fdct(data: &mut [f32; 64]) {
+            fdct_lanes::<$lanes>(data)
+        }
+
+        #[target_feature(enable = $feature)]
+        unsafe fn fdct_islow(data: &mut [i32; 64], precision: u8) {
+            fdct_islow_lanes::<$lanes>(data, precision)
+        }
+
+        #[target_feature(enable = $feature)]
+        unsafe fn rgb_to_ycbcr(planes: &mut [[f32; 256]; 4], len: usize, level_shift: f32) {
+            rgb_to_ycbcr_lanes::<$lanes>(planes, len, level_shift)
+        }
+
+        #[target_feature(enable = $feature)]
+        unsafe fn rgb_to_ycbcr_fixed(planes: &mut [[i32; 256]; 4], len: usize, center: i32) {
+            rgb_to_ycbcr_fixed_lanes::<$lanes>(planes, len, center)
+        }
+
+        #[target_feature(enable = $feature)]
+        unsafe fn quantize(du: &mut [i16; 64], dct_mcu: &[f32; 64], qt: &[f32; 64]) {
+            quantize_lanes::<$lanes>(du, dct_mcu, qt)
+        }
+
+        #[target_feature(enable = $feature)]
+        unsafe fn quantize_fixed(du: &mut [i16; 64], dct_mcu: &[f32; 64], divisors: &[i32; 64]) {
+            quantize_fixed_lanes::<$lanes>(du, dct_mcu, divisors)
+        }
+
+        pub const KERNELS: Kernels = Kernels {
+            fdct: |data| unsafe { fdct(data) },
+            fdct_islow: |data, precision| unsafe { fdct_islow(data, precision) },
+            rgb_to_ycbcr: |planes, len, level_shift| unsafe {
+                rgb_to_ycbcr(planes, len, level_shift)
+            },
+            rgb_to_ycbcr_fixed: |planes, len, center| unsafe {
+                rgb_to_ycbcr_fixed(planes, len, center)
+            },
+            quantize: |du, dct_mcu, qt| unsafe { quantize(du, dct_mcu, qt) },
+            quantize_fixed: |du, dct_mcu, divisors| unsafe {
+                quantize_fixed(du, dct_mcu, divisors)
+            },
+        };
+    };
+}
+
+// Pairs of 128-bit vectors.
+#[cfg(target_arch = "x86_64")]
+mod sse2 {
+    use std::arch::x86_64::*;
+    use super::*;
+
+    #[derive(Clone, Copy)]
+    pub struct F32x4x2(__m128, __m128);
+    #[derive(Clone, Copy)]
+    pub struct I32x4x2(__m128i, __m128i);
+
+    #[inline(always)]
+    unsafe fn transpose4(a: __m128, b: __m128, c: __m128, d: __m128) -> [__m128; 4] {
+        let t0 = _mm_unpacklo_ps(a, b);
+        let t1 = _mm_unpackhi_ps(a, b);
+        let t2 = _mm_unpacklo_ps(c, d);
+        let t3 = _mm_unpackhi_ps(c, d);
+        [_mm_movelh_ps(t0, t2), _mm_movehl_ps(t2, t0), _mm_movelh_ps(t1, t3), _mm_movehl_ps(t3, t1)]
+    }
+
+    // The 4x4 quarters are transposed, and the top right and bottom left ones
+    // swapped.
+    #[inline(always)]
+    unsafe fn transpose8(r: &mut [(__m128, __m128); 8]) {
+        let a = transpose4(r[0].0, r[1].0, r[2].0, r[3].0);
+        let b = transpose4(r[0].1, r[1].1, r[2].1, r[3].1);
+        let c = transpose4(r[4].0, r[5].0, r[6].0, r[7].0);
+        let d = transpose4(r[4].1, r[5].1, r[6].1, r[7].1);
+        for i in 0..4 {
+            r[i] = (a[i], c[i]);
+            r[i + 4] = (b[i], d[i]);
+        }
+    }
+
+    impl F32x8 for F32x4x2 {
+        type I = I32x4x2;
+
+        #[inline(always)]
+        unsafe fn splat(x: f32) -> Self {
+            F32x4x2(_mm_set1_ps(x), _mm_set1_ps(x))
+        }
+        #[inline(always)]
+        unsafe fn load(src: &[f32]) -> Self {
+            let p = src[..8].as_ptr();
+            F32x4x2(_mm_loadu_ps(p), _mm_loadu_ps(p.add(4)))
+        }
+        #[inline(always)]
+        unsafe fn store(self, dst: &mut [f32]) {
+            let p = dst[..8].as_mut_ptr();
+            _mm_storeu_ps(p, self.0);
+            _mm_storeu_ps(p.add(4), self.1);
+        }
+        #[inline(always)]
+        unsafe fn add(self, o: Self) -> Self {
+            F32x4x2(_mm_add_ps(self.0, o.0), _mm_add_ps(self.1, o.1))
+        }
+        #[inline(always)]
+        unsafe fn sub(self, o: Self) -> Self {
+            F32x4x2(_mm_sub_ps(self.0, o.0), _mm_sub_ps(self.1, o.1))
+        }
+        #[inline(always)]
+        unsafe fn mul(self, o: Self) -> Self {
+            F32x4x2(_mm_mul_ps(self.0, o.0), _mm_mul_ps(self.1, o.1))
+        }
+        #[inline(always)]
+        unsafe fn div(self, o: Self) -> Self {
+            F32x4x2(_mm_div_ps(self.0, o.0), _mm_div_ps(self.1, o.1))
+        }
+        // SSE2 has no rounding to integers: truncate, and take one from the
+        // values that went up.
+        #[inline(always)]
+        unsafe fn floor(self) -> Self {
+            let floor = |x: __m128| {
+                let t = _mm_cvtepi32_ps(_mm_cvttps_epi32(x));
+                _mm_sub_ps(t, _mm_and_ps(_mm_cmpgt_ps(t, x), _mm_set1_ps(1.0)))
+            };
+            F32x4x2(floor(self.0), floor(self.1))
+        }
+        #[inline(always)]
+        unsafe fn copysign(self, sign: Self) -> Self {
+            let mask = _mm_set1_ps(-0.0);
+            let copysign = |x: __m128, s: __m128| {
+                _mm_or_ps(_mm_andnot_ps(mask, x), _mm_and_ps(mask, s))
+            };
+            F32x4x2(copysign(self.0, sign.0), copysign(self.1, sign.1))
+        }
+        #[inline(always)]
+        unsafe fn trunc(self) -> I32x4x2 {
+            I32x4x2(_mm_cvttps_epi32(self.0), _mm_cvttps_epi32(self.1))
+        }
+        #[inline(always)]
+        unsafe fn from_i32(x: I32x4x2) -> Self {
+            F32x4x2(_mm_cvtepi32_ps(x.0), _mm_cvtepi32_ps(x.1))
+        }
+        #[inline(always)]
+        unsafe fn transpose(rows: &mut [Self; 8]) {
+            let mut r = [(_mm_setzero_ps(), _mm_setzero_ps()); 8];
+            for i in 0..8 {
+                r[i] = (rows[i].0, rows[i].1);
+            }
+            transpose8(&mut r);
+            for i in 0..8 {
+                rows[i] = F32x4x2(r[i].0, r[i].1);
+            }
+        }
+    }
+
+    impl I32x8 for I32x4x2 {
+        #[inline(always)]
+        unsafe fn splat(x: i32) -> Self {
+            I32x4x2(_mm_set1_epi32(x), _mm_set1_epi32(x))
+        }
+        #[inline(always)]
+        unsafe fn load(src: &[i32]) -> Self {
+            let p = src[..8].as_ptr() as *const __m128i;
+            I32x4x2(_mm_loadu_si128(p), _mm_loadu_si128(p.add(1)))
+        }
+        #[inline(always)]
+        unsafe fn store(self, dst: &mut [i32]) {
+            let p = dst[..8].as_mut_ptr() as *mut __m128i;
+            _mm_storeu_si128(p, self.0);
+            _mm_storeu_si128(p.add(1), self.1);
+        }
+        #[inline(always)]
+        unsafe fn add(self, o: Self) -> Self {
+            I32x4x2(_mm_add_epi32(self.0, o.0), _mm_add_epi32(self.1, o.1))
+        }
+        #[inline(always)]
+        unsafe fn sub(self, o: Self) -> Self {
+            I32x4x2(_mm_sub_epi32(self.0, o.0), _mm_sub_epi32(self.1, o.1))
+        }
+        // SSE2 only multiplies the even lanes into 64 bits: multiply the even
+        // and the odd lanes, and interleave the low halves.
+        #[inline(always)]
+        unsafe fn mul(self, o: Self) -> Self {
+            let mul = |a: __m128i, b: __m128i| {
+                let even = _mm_mul_epu32(a, b);
+                let odd = _mm_mul_epu32(_mm_srli_epi64(a, 32), _mm_srli_epi64(b, 32));
+                _mm_unpacklo_epi32(_mm_shuffle_epi32(even, 0b00_00_10_00),
+                                   _mm_shuffle_epi32(odd, 0b00_00_10_00))
+            };
+            I32x4x2(mul(self.0, o.0), mul(self.1, o.1))
+        }
+        #[inline(always)]
+        unsafe fn shl(self, n: i32) -> Self {
+            let n = _mm_cvtsi32_si128(n);
+            I32x4x2(_mm_sll_epi32(self.0, n), _mm_sll_epi32(self.1, n))
+        }
+        #[inline(always)]
+        unsafe fn sra(self, n: i32) -> Self {
+            let n = _mm_cvtsi32_si128(n);
+            I32x4x2(_mm_sra_epi32(self.0, n), _mm_sra_epi32(self.1, n))
+        }
+        #[inline(always)]
+        unsafe fn transpose(rows: &mut [Self; 8]) {
+            let mut r = [(_mm_setzero_ps(), _mm_setzero_ps()); 8];
+            for i in 0..8 {
+                r[i] = (_mm_castsi128_ps(rows[i].0), _mm_castsi128_ps(rows[i].1));
+            }
+            transpose8(&mut r);
+            for i in 0..8 {
+                rows[i] = I32x4x2(_mm_castps_si128(r[i].0), _mm_castps_si128(r[i].1));
+            }
+        }
+    }
+
+    kernels!("sse2", F32x4x2);
+}
+
+// One 256-bit vector.
+#[cfg(target_arch = "x86_64")]
+mod avx2 {
+    use std::arch::x86_64::*;
+    use super::*;
+
+    #[derive(Clone, Copy)]
+    pub struct F32x8x1(__m256);
+    #[derive(Clone, Copy)]
+    pub struct I32x8x1(__m256i);
+
+    // Interleaves pairs of rows, then pairs of pairs within each 128-bit
+    // half, then swaps the halves.
+    #[inline(always)]
+    unsafe fn transpose8(r: &mut [__m256; 8]) {
+        let t0 = _mm256_unpacklo_ps(r[0], r[1]);
+        let t1 = _mm256_unpackhi_ps(r[0], r[1]);
+        let t2 = _mm256_unpacklo_ps(r[2], r[3]);
+        let t3 = _mm256_unpackhi_ps(r[2], r[3]);
+        let t4 = _mm256_unpacklo_ps(r[4], r[5]);
+        let t5 = _mm256_unpackhi_ps(r[4], r[5]);
+        let t6 = _mm256_unpacklo_ps(r[6], r[7]);
+        let t7 = _mm256_unpackhi_ps(r[6], r[7]);
+        let u0 = _mm256_shuffle_ps(t0, t2, 0x44);
+        let u1 = _mm256_shuffle_ps(t0, t2, 0xee);
+        let u2 = _mm256_shuffle_ps(t1, t3, 0x44);
+        let u3 = _mm256_shuffle_ps(t1, t3, 0xee);
+        let u4 = _mm256_shuffle_ps(t4, t6, 0x44);
+        let u5 = _mm256_shuffle_ps(t4, t6, 0xee);
+        let u6 = _mm256_shuffle_ps(t5, t7, 0x44);
+        let u7 = _mm256_shuffle_ps(t5, t7, 0xee);
+        r[0] = _mm256_permute2f128_ps(u0, u4, 0x20);
+        r[1] = _mm256_permute2f128_ps(u1, u5, 0x20);
+        r[2] = _mm256_permute2f128_ps(u2, u6, 0x20);
+        r[3] = _mm256_permute2f128_ps(u3, u7, 0x20);
+        r[4] = _mm256_permute2f128_ps(u0, u4, 0x31);
+        r[5] = _mm256_permute2f128_ps(u1, u5, 0x31);
+        r[6] = _mm256_permute2f128_ps(u2, u6, 0x31);
+        r[7] = _mm256_permute2f128_ps(u3, u7, 0x31);
+    }
+
+    impl F32x8 for F32x8x1 {
+        type I = I32x8x1;
+
+        #[inline(always)]
+        unsafe fn splat(x: f32) -> Self {
+            F32x8x1(_mm256_set1_ps(x))
+        }
+        #[inline(always)]
+        unsafe fn load(src: &[f32]) -> Self {
+            F32x8x1(_mm256_loadu_ps(src[..8].as_ptr()))
+        }
+        #[inline(always)]
+        unsafe fn store(self, dst: &mut [f32]) {
+            _mm256_storeu_ps(dst[..8].as_mut_ptr(), self.0)
+        }
+        #[inline(always)]
+        unsafe fn add(self, o: Self) -> Self {
+            F32x8x1(_mm256_add_ps(self.0, o.0))
+        }
+        #[inline(always)]
+        unsafe fn sub(self, o: Self) -> Self {
+            F32x8x1(_mm256_sub_ps(self.0, o.0))
+        }
+        #[inline(always)]
+        unsafe fn mul(self, o: Self) -> Self {
+            F32x8x1(_mm256_mul_ps(self.0, o.0))
+        }
+        #[inline(always)]
+        unsafe fn div(self, o: Self) -> Self {
+            F32x8x1(_mm256_div_ps(self.0, o.0))
+        }
+        #[inline(always)]
+        unsafe fn floor(self) -> Self {
+            F32x8x1(_mm256_floor_ps(self.0))
+        }
+        #[inline(always)]
+        unsafe fn copysign(self, sign: Self) -> Self {
+            let mask = _mm256_set1_ps(-0.0);
+            F32x8x1(_mm256_or_ps(_mm256_andnot_ps(mask, self.0), _mm256_and_ps(mask, sign.0)))
+        }
+        #[inline(always)]
+        unsafe fn trunc(self) -> I32x8x1 {
+            I32x8x1(_mm256_cvttps_epi32(self.0))
+        }
+        #[inline(always)]
+        unsafe fn from_i32(x: I32x8x1) -> Self {
+            F32x8x1(_mm256_cvtepi32_ps(x.0))
+        }
+        #[inline(always)]
+        unsafe fn transpose(rows: &mut [Self; 8]) {
+            let mut r = [_mm256_setzero_ps(); 8];
+            for i in 0..8 {
+                r[i] = rows[i].0;
+            }
+            transpose8(&mut r);
+            for i in 0..8 {
+                rows[i] = F32x8x1(r[i]);
+            }
+        }
+    }
+
+    impl I32x8 for I32x8x1 {
+        #[inline(always)]
+        unsafe fn splat(x: i32) -> Self {
+            I32x8x1(_mm256_set1_epi32(x))
+        }
+        #[inline(always)]
+        unsafe fn load(src: &[i32]) -> Self {
+            I32x8x1(_mm256_loadu_si256(src[..8].as_ptr() as *const __m256i))
+        }
+        #[inline(always)]
+        unsafe fn store(self, dst: &mut [i32]) {
+            _mm256_storeu_si256(dst[..8].as_mut_ptr() as *mut __m256i, self.0)
+        }
+        #[inline(always)]
+        unsafe fn add(self, o: Self) -> Self {
+            I32x8x1(_mm256_add_epi32(self.0, o.0))
+        }
+        #[inline(always)]
+        unsafe fn sub(self, o: Self) -> Self {
+            I32x8x1(_mm256_sub_epi32(self.0, o.0))
+        }
+        #[inline(always)]
+        unsafe fn mul(self, o: Self) -> Self {
+            I32x8x1(_mm256_mullo_epi32(self.0, o.0))
+        }
+        #[inline(always)]
+        unsafe fn shl(self, n: i32) -> Self {
+            I32x8x1(_mm256_sll_epi32(self.0, _mm_cvtsi32_si128(n)))
+        }
+        #[inline(always)]
+        unsafe fn sra(self, n: i32) -> Self {
+            I32x8x1(_mm256_sra_epi32(self.0, _mm_cvtsi32_si128(n)))
+        }
+        #[inline(always)]
+        unsafe fn transpose(rows: &mut [Self; 8]) {
+            let mut r = [_mm256_setzero_ps(); 8];
+            for i in 0..8 {
+                r[i] = _mm256_castsi256_ps(rows[i].0);
+            }
+            transpose8(&mut r);
+            for i in 0..8 {
+                rows[i] = I32x8x1(_mm256_castps_si256(r[i]));
+            }
+        }
+    }
+
+    kernels!("avx2", F32x8x1);
+}
+
+// Pairs of 128-bit vectors.
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use std::arch::aarch64::*;
+    use super::*;
+
+    #[derive(Clone, Copy)]
+    pub struct F32x4x2(float32x4_t, float32x4_t);
+    #[derive(Clone, Copy)]
+    pub struct I32x4x2(int32x4_t, int32x4_t);
+
+    #[inline(always)]
+    unsafe fn transpose4(a: float32x4_t, b: float32x4_t, c: float32x4_t, d: float32x4_t)
+                         -> [float32x4_t; 4] {
+        let ab = vtrnq_f32(a, b);
+        let cd = vtrnq_f32(c, d);
+        [vcombine_f32(vget_low_f32(ab.0), vget_low_f32(cd.0)),
+         vcombine_f32(vget_low_f32(ab.1), vget_low_f32(cd.1)),
+         vcombine_f32(vget_high_f32(ab.0), vget_high_f32(cd.0)),
+         vcombine_f32(vget_high_f32(ab.1), vget_high_f32(cd.1))]
+    }
+
+    // As with SSE2, the 4x4 quarters are transposed, and the top right and
+    // bottom left ones swapped.
+    #[inline(always)]
+    unsafe fn transpose8(r: &mut [(float32x4_t, float32x4_t); 8]) {
+        let a = transpose4(r[0].0, r[1].0, r[2].0, r[3].0);
+        let b = transpose4(r[0].1, r[1].1, r[2].1, r[3].1);
+        let c = transpose4(r[4].0, r[5].0, r[6].0, r[7].0);
+        let d = transpose4(r[4].1, r[5].1, r[6].1, r[7].1);
+        for i in 0..4 {
+            r[i] = (a[i], c[i]);
+            r[i + 4] = (b[i], d[i]);
+        }
+    }
+
+    impl F32x8 for F32x4x2 {
+        type I = I32x4x2;
+
+        #[inline(always)]
+        unsafe fn splat(x: f32) -> Self {
+            F32x4x2(vdupq_n_f32(x), vdupq_n_f32(x))
+        }
+        #[inline(always)]
+        unsafe fn load(src: &[f32]) -> Self {
+            let p = src[..8].as_ptr();
+            F32x4x2(vld1q_f32(p), vld1q_f32(p.add(4)))
+        }
+        #[inline(always)]
+        unsafe fn store(self, dst: &mut [f32]) {
+            let p = dst[..8].as_mut_ptr();
+            vst1q_f32(p, self.0);
+            vst1q_f32(p.add(4), self.1);
+        }
+        #[inline(always)]
+        unsafe fn add(self, o: Self) -> Self {
+            F32x4x2(vaddq_f32(self.0, o.0), vaddq_f32(self.1, o.1))
+        }
+        #[inline(always)]
+        unsafe fn sub(self, o: Self) -> Self {
+            F32x4x2(vsubq_f32(self.0, o.0), vsubq_f32(self.1, o.1))
+        }
+        #[inline(always)]
+        unsafe fn mul(self, o: Self) -> Self {
+            F32x4x2(vmulq_f32(self.0, o.0), vmulq_f32(self.1, o.1))
+        }
+        #[inline(always)]
+        unsafe fn div(self, o: Self) -> Self {
+            F32x4x2(vdivq_f32(self.0, o.0), vdivq_f32(self.1, o.1))
+        }
+        #[inline(always)]
+        unsafe fn floor(self) -> Self {
+            F32x4x2(vrndmq_f32(self.0), vrndmq_f32(self.1))
+        }
+        #[inline(always)]
+        unsafe fn copysign(self, sign: Self) -> Self {
+            let mask = vdupq_n_u32(0x8000_0000);
+            F32x4x2(vbslq_f32(mask, sign.0, self.0), vbslq_f32(mask, sign.1, self.1))
+        }
+        #[inline(always)]
+        unsafe fn trunc(self) -> I32x4x2 {
+            I32x4x2(vcvtq_s32_f32(self.0), vcvtq_s32_f32(self.1))
+        }
+        #[inline(always)]
+        unsafe fn from_i32(x: I32x4x2) -> Self {
+            F32x4x2(vcvtq_f32_s32(x.0), vcvtq_f32_s32(x.1))
+        }
+        #[inline(always)]
+        unsafe fn transpose(rows: &mut [Self; 8]) {
+            let mut r = [(vdupq_n_f32(0.0), vdupq_n_f32(0.0)); 8];
+            for i in 0..8 {
+                r[i] = (rows[i].0, rows[i].1);
+            }
+            transpose8(&mut r);
+            for i in 0..8 {
+                rows[i] = F32x4x2(r[i].0, r[i].1);
+            }
+        }
+    }
+
+    impl I32x8 for I32x4x2 {
+        #[inline(always)]
+        unsafe fn splat(x: i32) -> Self {
+            I32x4x2(vdupq_n_s32(x), vdupq_n_s32(x))
+        }
+        #[inline(always)]
+        unsafe fn load(src: &[i32]) -> Self {
+            let p = src[..8].as_ptr();
+            I32x4x2(vld1q_s32(p), vld1q_s32(p.add(4)))
+        }
+        #[inline(always)]
+        unsafe fn store(self, dst: &mut [i32]) {
+            let p = dst[..8].as_mut_ptr();
+            vst1q_s32(p, self.0);
+            vst1q_s32(p.add(4), self.1);
+        }
+        #[inline(always)]
+        unsafe fn add(self, o: Self) -> Self {
+            I32x4x2(vaddq_s32(self.0, o.0), vaddq_s32(self.1, o.1))
+        }
+        #[inline(always)]
+        unsafe fn sub(self, o: Self) -> Self {
+            I32x4x2(vsubq_s32(self.0, o.0), vsubq_s32(self.1, o.1))
+        }
+        #[inline(always)]
+        unsafe fn mul(self, o: Self) -> Self {
+            I32x4x2(vmulq_s32(self.0, o.0), vmulq_s32(self.1, o.1))
+        }
+        #[inline(always)]
+        unsafe fn shl(self, n: i32) -> Self {
+            let n = vdupq_n_s32(n);
+            I32x4x2(vshlq_s32(self.0, n), vshlq_s32(self.1, n))
+        }
+        // Shifting left by a negative count shifts right.
+        #[inline(always)]
+        unsafe fn sra(self, n: i32) -> Self {
+            let n = vdupq_n_s32(-n);
+            I32x4x2(vshlq_s32(self.0, n), vshlq_s32(self.1, n))
+        }
+        #[inline(always)]
+        unsafe fn transpose(rows: &mut [Self; 8]) {
+            let mut r = [(vdupq_n_f32(0.0), vdupq_n_f32(0.0)); 8];
+            for i in 0..8 {
+                r[i] = (vreinterpretq_f32_s32(rows[i].0), vreinterpretq_f32_s32(rows[i].1));
+            }
+            transpose8(&mut r);
+            for i in 0..8 {
+                rows[i] = I32x4x2(vreinterpretq_s32_f32(r[i].0), vreinterpretq_s32_f32(r[i].1));
+            }
+        }
+    }
+
+    kernels!("neon", F32x4x2);
+}
@@ -0,0 +1,184 @@
+/* Encoding an image as its rows arrive.
+
+   Rows are gathered until they complete a row of MCUs, 8 or 16 rows of
+   pixels, which is then converted, transformed, quantized and coded, and its
+   entropy-coded data written to the sink. The MCUs go through the same steps
+   in the same order as when the whole image is encoded at once, so the
+   output is the same as that of `encode_image`. Optimized Huffman tables,
+   progressive and arithmetic coding and lossless JPEGs need the whole image
+   before the scan can be written, and aren't supported. */
+
+use std::io;
+use std::io::Write;
+
+use super::{
+    append_header, append_sequential_header, check_dimensions, effective_restart_interval,
+    BlockSource, EncodeError, EncoderOptions, Frame, ImageView, McuQuantizer, PixelFormat,
+    Quantizer, Sample, ScanCoder, State, Writer,
+};
+
+/// Encodes an image as its rows arrive, writing each row of MCUs to a sink as
+/// soon as it is complete. Only that row of MCUs, 8 or 16 rows of pixels, is
+/// held in memory.
+pub struct StreamEncoder<W: Write, T: Sample = u8> {
+    sink: W,
+    format: PixelFormat,
+    frame: Frame,
+    mem: State,
+    quant: Quantizer,
+    restart_interval: usize,
+    mcus: McuQuantizer,
+    coder: ScanCoder,
+    // The rows of the current row of MCUs received so far, tightly packed.
+    strip: Vec<T>,
+    // Number of rows received.
+    rows: usize,
+    // The kind of the error that the sink failed with, after which the
+    // encoder is left partway through a row and refuses to go on.
+    failed: Option<io::ErrorKind>,
+}
+
+impl<W: Write, T: Sample> StreamEncoder<W, T> {
+    /// Writes the headers of a `width` x `height` image in `format` to
+    /// `sink`, and returns an encoder for its rows. `u16` samples give a
+    /// 12-bit JPEG.
+    ///
+    /// Fails if the image is empty or larger than 65535 pixels either way,
+    /// if its tables are invalid, or if `options` ask for anything that needs
+    /// the whole image: optimized Huffman tables, which 12-bit JPEGs also use
+    /// unless `huffman_tables` are given, or a progressive, arithmetic-coded
+    /// or lossless JPEG. The rows are coded on the calling thread, but
+    /// `threads` rounds `restart_interval` as it does for `encode_image`.
+    pub fn new(
+        mut sink: W,
+        options: &EncoderOptions,
+        width: u32,
+        height: u32,
+        format: PixelFormat,
+    ) -> Result<StreamEncoder<W, T>, EncodeError> {
+        check_dimensions(width as i64, height as i64)?;
+        let custom = !options.huffman_tables.is_empty();
+        if options.optimize_huffman || (T::PRECISION > 8 && !custom) {
+            return Err(EncodeError::UnsupportedOptions("optimized Huffman tables"));
+        }
+        if options.progressive || options.arithmetic_coding || options.lossless.is_some() {
+            return Err(EncodeError::UnsupportedOptions("coding that needs the whole image"));
+        }
+
+        // Only the layout of the image matters to the frame.
+        let frame = Frame::new(&ImageView::<T>::new(&[], width, height, format), options);
+        let mem = State::new(options, T::PRECISION)?;
+        let restart_interval = effective_restart_interval(options, &frame);
+        let quant = Quantizer::new(&mem, options);
+
+        let mut out = Writer::new(&mut sink);
+        append_header(&mut out, &mem, options, &frame, restart_interval);
+        append_sequential_header(&mut out, &mem, &frame);
+        out.finish()?;
+
+        Ok(StreamEncoder {
+            sink,
+            format,
+            mcus: McuQuantizer::new(&frame, restart_interval, 0),
+            coder: ScanCoder::new(&frame, 0),
+            frame,
+            mem,
+            quant,
+            restart_interval,
+            strip: vec![],
+            rows: 0,
+            failed: None,
+        })
+    }
+
+    // Fails if the sink has failed before.
+    fn check_sink(&self) -> Result<(), EncodeError> {
+        match self.failed {
+            Some(kind) => Err(EncodeError::Io(io::Error::new(kind, "the sink failed earlier"))),
+            None => Ok(()),
+        }
+    }
+
+    /// Encodes the next rows of the image, top-down and tightly packed, each
+    /// `width * format.bytes_per_pixel()` samples. Any number of whole rows
+    /// can be given at a time. Every row of MCUs that they complete is
+    /// written to the sink.
+    ///
+    /// Fails if `data` isn't a whole number of rows, or goes past the last
+    /// row of the image, before any of it is encoded. Once the sink has
+    /// failed, this and `finish` fail with an error of the same kind.
+    pub fn push_rows(&mut self, data: &[T]) -> Result<(), EncodeError> {
+        self.check_sink()?;
+        let row_len = self.frame.width * self.format.bytes_per_pixel();
+        let rest = (self.frame.height - self.rows) * row_len;
+        if data.len() % row_len != 0 || data.len() > rest {
+            let expected = data.len().div_ceil(row_len) * row_len;
+            let expected = expected.min(rest);
+            return Err(EncodeError::BufferLength {
+                expected,
+                actual: data.len(),
+            });
+        }
+        let mcu_h = 8 * self.frame.v_samp;
+        for row in data.chunks(row_len) {
+            self.strip.extend_from_slice(row);
+            self.rows += 1;
+            if self.rows % mcu_h == 0 || self.rows == self.frame.height {
+                if let Err(e) = self.encode_strip() {
+                    self.failed = Some(e.kind());
+                    return Err(e.into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Encodes the row of MCUs in `strip`, and writes the whole bytes of its
+    // entropy-coded data to the sink.
+    fn encode_strip(&mut self) -> io::Result<()> {
+        /* The strip is transformed as a frame of one row of MCUs. In the
+           last one, as at the bottom of the image, the last row of pixels
+           repeats to fill the MCUs. */
+        let height = self.strip.len() / (self.frame.width * self.format.bytes_per_pixel());
+        let rows = ImageView::new(&self.strip, self.frame.width as u32, height as u32, self.format);
+        let strip_frame = Frame {
+            height,
+            ..self.frame.clone()
+        };
+
+        let frame = &self.frame;
+        let (mem, quant, restart_interval) = (&self.mem, &self.quant, self.restart_interval);
+        let (mcus, coder) = (&mut self.mcus, &mut self.coder);
+        let mut out = Writer::new(&mut self.sink);
+        rows.for_each_transformed_mcu(&strip_frame, quant.kernels, 0..1, &mut |mcu| {
+            mcus.push(frame, quant, mcu, &mut |mcu| {
+                coder.encode_mcu(&mut out, mem, frame, restart_interval, mcu)
+            });
+        });
+        out.finish()?;
+
+        self.strip.clear();
+        Ok(())
+    }
+
+    /// Writes the end of the image, and returns the sink.
+    ///
+    /// Fails if rows of the image haven't been given, or if the sink has
+    /// failed.
+    pub fn finish(mut self) -> Result<W, EncodeError> {
+        self.check_sink()?;
+        if self.rows < self.frame.height {
+            let row_len = self.frame.width * self.format.bytes_per_pixel();
+            return Err(EncodeError::BufferLength {
+                expected: self.frame.height * row_len,
+                actual: self.rows * row_len,
+            });
+        }
+        let mut out = Writer::new(&mut self.sink);
+        self.coder.finish(&mut out);
+        // EOI
+        out.write_u16(0xffd9);
+        out.finish()?;
+        Ok(self.sink)
+    }
+}
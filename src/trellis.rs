@@ -0,0 +1,209 @@
+/* Trellis quantization.
+
+   Rounding every coefficient to the nearest multiple of its quantization
+   step gives the least distortion, but not the smallest file for that
+   distortion. The trellis instead picks the values that minimize the bits
+   they take plus lambda times the distortion they cause, measured in
+   squared quantization steps. The rates come from the code lengths of the
+   Huffman tables.
+
+   In a block, the cost of an AC coefficient depends on the run of zeros
+   before it, so the choice of the last nonzero coefficient before each
+   position is a shortest path problem over the positions of the block. The
+   DC coefficients are coded as differences from the previous block of the
+   same component, which makes the DC values of a row of blocks a shortest
+   path problem of their own. This follows mozjpeg's `quantize_trellis` and
+   `quantize_trellis_dc`. */
+
+use super::{calculate_variable_length_int, State};
+
+// Bits taken by a symbol with a code of `len` bits. Symbols without a code,
+// which only the example tables of 12-bit frames lack, count as the longest
+// code.
+fn code_bits(len: u8) -> f32 {
+    if len == 0 {
+        16.0
+    } else {
+        len as f32
+    }
+}
+
+// Number of bits in the magnitude category of `val`.
+fn category(val: i32) -> usize {
+    if val == 0 {
+        0
+    } else {
+        calculate_variable_length_int(val).1 as usize
+    }
+}
+
+#[derive(Clone)]
+pub struct Trellis {
+    lambda: f32,
+    // Code lengths of the DC and AC Huffman tables in each slot.
+    dc_len: Vec<[u8; 257]>,
+    ac_len: Vec<[u8; 257]>,
+}
+
+impl Trellis {
+    pub fn new(lambda: f32, mem: &State) -> Trellis {
+        Trellis {
+            lambda,
+            dc_len: mem.ehuffsize.iter().step_by(2).cloned().collect(),
+            ac_len: mem.ehuffsize.iter().skip(1).step_by(2).cloned().collect(),
+        }
+    }
+
+    // The slot of the tables that the rates are taken from, given the slot
+    // that a component is coded with. Frames whose tables are fitted to the
+    // image can use slots that `State` has no tables in, which count the bits
+    // of its last ones.
+    fn slot(&self, slot: usize) -> usize {
+        slot.min(self.dc_len.len() - 1)
+    }
+
+    /* Chooses the AC coefficients of a block coded with the Huffman tables
+       in `slot`. `coefs` are the DCT coefficients divided by their
+       quantization steps, in zigzag order. */
+    pub fn quantize_ac(&self, du: &mut [i16; 64], coefs: &[f32; 64], slot: usize) {
+        let ac_len = &self.ac_len[self.slot(slot)];
+        let lambda = self.lambda;
+
+        // Distortion of zeroing every coefficient from 1 to i.
+        let mut zero_dist = [0f32; 64];
+        for i in 1..64 {
+            zero_dist[i] = zero_dist[i - 1] + coefs[i] * coefs[i];
+        }
+
+        /* cost[i] is the least cost of coding coefficients 1 to i with i the
+           last one that isn't zero, and prev[i] and value[i] say how it is
+           reached. Position 0 stands for the start of the block. */
+        let mut cost = [f32::INFINITY; 64];
+        let mut prev = [0usize; 64];
+        let mut value = [0i32; 64];
+        cost[0] = 0.0;
+        for i in 1..64 {
+            let magnitude = coefs[i].abs();
+            let rounded = (magnitude + 0.5).floor() as i32;
+            if rounded == 0 {
+                continue;
+            }
+            /* Within a magnitude category, every value takes the same number
+               of bits, so the largest value of each smaller category is the
+               only one worth trying. */
+            let mut candidates = [0i32; 16];
+            candidates[0] = rounded;
+            let mut count = 1;
+            for size in 1..category(rounded) {
+                candidates[count] = (1 << size) - 1;
+                count += 1;
+            }
+            for j in (0..i).rev() {
+                if cost[j] == f32::INFINITY {
+                    continue;
+                }
+                let run = i - j - 1;
+                let base = cost[j]
+                    + lambda * (zero_dist[i - 1] - zero_dist[j])
+                    + (run / 16) as f32 * code_bits(ac_len[0xf0]);
+                for &v in candidates[..count].iter() {
+                    let size = category(v);
+                    let error = magnitude - v as f32;
+                    let total = base
+                        + code_bits(ac_len[((run % 16) << 4) | size])
+                        + size as f32
+                        + lambda * error * error;
+                    if total < cost[i] {
+                        cost[i] = total;
+                        prev[i] = j;
+                        value[i] = if coefs[i] < 0.0 { -v } else { v };
+                    }
+                }
+            }
+        }
+
+        // The block ends after the last coefficient that isn't zero, with an
+        // EOB unless that is the last coefficient of the block.
+        let mut last = 0;
+        let mut best = f32::INFINITY;
+        for i in 0..64 {
+            if cost[i] == f32::INFINITY {
+                continue;
+            }
+            let eob = if i < 63 { code_bits(ac_len[0]) } else { 0.0 };
+            let total = cost[i] + lambda * (zero_dist[63] - zero_dist[i]) + eob;
+            if total < best {
+                best = total;
+                last = i;
+            }
+        }
+
+        du[1..].fill(0);
+        while last > 0 {
+            du[last] = value[last] as i16;
+            last = prev[last];
+        }
+    }
+
+    /* Chooses the DC coefficients of a run of blocks of a component coded
+       with the Huffman tables in `slot`, in coding order, given the DC
+       coefficients divided by their quantization steps. `resets[k]` says
+       whether the prediction starts over at block k; otherwise the first
+       block is predicted from `pred`, which is left holding the last value. */
+    pub fn quantize_dc(
+        &self,
+        dcs: &[f32],
+        resets: &[bool],
+        pred: &mut i32,
+        slot: usize,
+    ) -> Vec<i16> {
+        let dc_len = &self.dc_len[self.slot(slot)];
+        let lambda = self.lambda;
+        let n = dcs.len();
+        let rate = |diff: i32| {
+            let cat = category(diff);
+            code_bits(dc_len[cat]) + cat as f32
+        };
+
+        // Each block rounds to the nearest value or one of its neighbours.
+        const NUM_CANDIDATES: usize = 3;
+        let mut values = vec![[0i32; NUM_CANDIDATES]; n];
+        let mut cost = vec![[0f32; NUM_CANDIDATES]; n];
+        let mut from = vec![[0usize; NUM_CANDIDATES]; n];
+        for k in 0..n {
+            let rounded = (dcs[k] + 0.5).floor() as i32;
+            for s in 0..NUM_CANDIDATES {
+                let v = rounded + s as i32 - 1;
+                let error = dcs[k] - v as f32;
+                values[k][s] = v;
+                cost[k][s] = f32::INFINITY;
+                for t in 0..NUM_CANDIDATES {
+                    let (before, p) = if k == 0 {
+                        (0.0, if resets[0] { 0 } else { *pred })
+                    } else {
+                        (cost[k - 1][t], if resets[k] { 0 } else { values[k - 1][t] })
+                    };
+                    let total = before + rate(v - p) + lambda * error * error;
+                    if total < cost[k][s] {
+                        cost[k][s] = total;
+                        from[k][s] = t;
+                    }
+                }
+            }
+        }
+
+        let mut out = vec![0i16; n];
+        if n == 0 {
+            return out;
+        }
+        let mut s = (0..NUM_CANDIDATES)
+            .min_by(|&a, &b| cost[n - 1][a].total_cmp(&cost[n - 1][b]))
+            .unwrap();
+        *pred = values[n - 1][s];
+        for k in (0..n).rev() {
+            out[k] = values[k][s] as i16;
+            s = from[k][s];
+        }
+        out
+    }
+}
@@ -0,0 +1,99 @@
+/* Output of the encoder.
+
+Markers, segments and entropy-coded bytes are gathered in a buffer, which
+is written to the sink whenever a chunk of it is full, so that only a chunk
+of the output is held in memory. Writing bytes can't fail: the first error
+from the sink stops the output, and is returned once the encoder is done.
+Without a sink, the buffer keeps every byte. */
+
+use std::io;
+use std::io::Write;
+
+// Bytes gathered before they are written to the sink.
+pub const CHUNK: usize = 1 << 16;
+
+pub struct Writer<'a> {
+    buf: Vec<u8>,
+    sink: Option<&'a mut dyn Write>,
+    // Length the buffer is written out at.
+    limit: usize,
+    // The first error from the sink, after which nothing more is written.
+    error: Option<io::Error>,
+}
+
+impl<'a> Writer<'a> {
+    // Writes to `sink` in chunks.
+    pub fn new(sink: &'a mut dyn Write) -> Writer<'a> {
+        Writer {
+            buf: vec![],
+            sink: Some(sink),
+            limit: CHUNK,
+            error: None,
+        }
+    }
+
+    // Keeps every byte, for `into_bytes`.
+    pub fn buffer() -> Writer<'a> {
+        Writer::reuse(vec![])
+    }
+
+    // Keeps every byte in `buf`, which is cleared first, so that its
+    // capacity is used again.
+    pub fn reuse(mut buf: Vec<u8>) -> Writer<'a> {
+        buf.clear();
+        Writer {
+            buf,
+            sink: None,
+            limit: usize::MAX,
+            error: None,
+        }
+    }
+
+    #[inline]
+    pub fn push(&mut self, byte: u8) {
+        self.buf.push(byte);
+        if self.buf.len() >= self.limit {
+            self.drain();
+        }
+    }
+
+    pub fn extend_from_slice(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+        if self.buf.len() >= self.limit {
+            self.drain();
+        }
+    }
+
+    // Writes `value` big-endian, as every 16-bit field of a JPEG is.
+    pub fn write_u16(&mut self, value: u16) {
+        self.extend_from_slice(&value.to_be_bytes());
+    }
+
+    // Writes the buffer to the sink.
+    fn drain(&mut self) {
+        if let Some(ref mut sink) = self.sink {
+            if self.error.is_none() {
+                if let Err(e) = sink.write_all(&self.buf) {
+                    self.error = Some(e);
+                }
+            }
+        }
+        self.buf.clear();
+    }
+
+    // Writes the rest of the buffer to the sink, and returns the first error
+    // from the sink, if any.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.drain();
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    // The bytes of a writer without a sink.
+    pub fn into_bytes(self) -> Vec<u8> {
+        debug_assert!(self.sink.is_none());
+        self.buf
+    }
+}